@@ -1,6 +1,11 @@
+use std::env;
+use std::fs::{self, File};
 use std::path::Path;
 use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use dither::{clamp_f64_to_u8, ditherer};
 use dither::color::palette;
@@ -10,11 +15,14 @@ use gumdrop::Options;
 use image;
 use image::{DynamicImage, GenericImageView};
 use image::imageops::FilterType;
-use linux_embedded_hal::{CdevPin, Delay, spidev::{SpidevOptions, SpiModeFlags}, Spidev};
-use linux_embedded_hal::gpio_cdev::{Chip, LineRequestFlags};
 
-use epdriver::{DisplayError, EPaper75TriColour, EPaper75TriColourV2, EPaperDisplay};
-use epdriver::display_connector::SpiConnector;
+use epdriver::{Border, DisplayError, EPaper75TriColour, EPaper75TriColourV2, EPaperDisplay};
+use epdriver::dither::TRI_COLOUR_PALETTE;
+use epdriver::display_connector::DisplayConnector;
+use epdriver::gd7965::{self, GD7965};
+use epdriver::linux::{spidev_bufsiz, LinuxConnector, LinuxConnectorBuilder};
+use epdriver::record::{self, Event, Events, RecordingConnector};
+use epdriver::UcFamilyController;
 
 use crate::CropAlign::{Centre, TopLeft};
 
@@ -43,6 +51,35 @@ impl FromStr for CropAlign {
     }
 }
 
+#[derive(Debug)]
+struct BorderOption(Border);
+
+impl FromStr for BorderOption {
+    type Err = String;
+    fn from_str(border: &str) -> Result<Self, Self::Err> {
+        match border.to_lowercase().as_str() {
+            "white"|"w" => Ok(BorderOption(Border::White)),
+            "black"|"b" => Ok(BorderOption(Border::Black)),
+            "accent"|"a" => Ok(BorderOption(Border::Accent)),
+            "floating"|"f" => Ok(BorderOption(Border::Floating)),
+            _ => Err(format!("failed to parse border option from {}", border )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NumberList(Vec<u32>);
+
+impl FromStr for NumberList {
+    type Err = String;
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        list.split(',')
+            .map(|n| n.trim().parse().map_err(|_| format!("failed to parse number from {}", n)))
+            .collect::<Result<Vec<u32>, String>>()
+            .map(NumberList)
+    }
+}
+
 #[derive(Debug, Options)]
 struct CommandLineOptions {
 
@@ -52,6 +89,40 @@ struct CommandLineOptions {
     #[options(help = "more information on stdout")]
     verbose: bool,
 
+    #[options(help = "colour of the border around the image", meta="[white|w|black|b|accent|a|floating|f]" )]
+    border: Option<BorderOption>,
+
+    #[options(help = "record everything sent to the display to a file, see the replay command", meta="FILE")]
+    record: Option<PathBuf>,
+
+    #[options(command)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Options)]
+enum Command {
+    #[options(help = "dither an image and show it on the display")]
+    Show(ShowOptions),
+
+    #[options(help = "cycle black, white and accent frames to remove ghosting")]
+    Clean(CleanOptions),
+
+    #[options(help = "check the display is connected and responding")]
+    Check(CheckOptions),
+
+    #[options(help = "time frame transfers for different chunk sizes and SPI clocks")]
+    Bench(BenchOptions),
+
+    #[options(help = "send a recording made with --record to the display")]
+    Replay(ReplayOptions),
+}
+
+#[derive(Debug, Options)]
+struct ShowOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
     #[options(help = "specify image alignment if image needs to be cropped", meta="[topleft|tl|bottomright|br|centre|c]" )]
     crop_align: Option<CropAlign>,
 
@@ -66,18 +137,170 @@ struct CommandLineOptions {
     image_file: PathBuf,
 }
 
+/// `epdither [OPTIONS] <image>`, what `show` was before there were
+/// commands. gumdrop won't mix free arguments and commands in one struct.
+#[derive(Debug, Options)]
+struct BareShowOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
+    #[options(help = "more information on stdout")]
+    verbose: bool,
+
+    #[options(help = "colour of the border around the image", meta="[white|w|black|b|accent|a|floating|f]" )]
+    border: Option<BorderOption>,
+
+    #[options(help = "record everything sent to the display to a file", meta="FILE")]
+    record: Option<PathBuf>,
+
+    #[options(help = "specify image alignment if image needs to be cropped", meta="[topleft|tl|bottomright|br|centre|c]" )]
+    crop_align: Option<CropAlign>,
+
+    #[options(help = "flip image vertically - around the horizontal axis")]
+    flipv: bool,
+
+    #[options(help = "flip image horizontal - around the vertical axis")]
+    fliph: bool,
+
+    /// Files to process
+    #[options(free)]
+    image_file: PathBuf,
+}
+
+impl From<BareShowOptions> for CommandLineOptions {
+    fn from(bare: BareShowOptions) -> Self {
+        CommandLineOptions {
+            help: false,
+            verbose: bare.verbose,
+            border: bare.border,
+            record: bare.record,
+            command: Some(Command::Show(ShowOptions {
+                help: false,
+                crop_align: bare.crop_align,
+                flipv: bare.flipv,
+                fliph: bare.fliph,
+                image_file: bare.image_file,
+            })),
+        }
+    }
+}
+
+#[derive(Debug, Options)]
+struct CleanOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
+    #[options(help = "keep running and clean again every given number of minutes", meta="MINUTES")]
+    every: Option<u64>,
+}
+
+#[derive(Debug, Options)]
+struct CheckOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Debug, Options)]
+struct BenchOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
+    #[options(help = "chunk sizes to try, defaults to 1024 and the spidev limit", meta="BYTES,...")]
+    chunk_sizes: Option<NumberList>,
+
+    #[options(help = "SPI clock speeds to try", meta="HZ,...", default="1000000,4000000,10000000")]
+    speeds: NumberList,
+
+    #[options(help = "frames to send for every combination", meta="N", default="3")]
+    frames: u32,
+}
+
+#[derive(Debug, Options)]
+struct ReplayOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
+    #[options(help = "print the recorded events instead of sending them")]
+    dump: bool,
+
+    /// Recording to replay
+    #[options(free)]
+    recording: PathBuf,
+}
+
+// falls back to the bare image form when no command is given, anything
+// else, including errors and help, is left to gumdrop
+fn parse_options() -> CommandLineOptions {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let with_command = CommandLineOptions::parse_args_default(&args);
+    if !matches!(&with_command, Ok(opt) if opt.command.is_some() || opt.help) {
+        if let Ok(bare) = BareShowOptions::parse_args_default(&args) {
+            if !bare.help && !bare.image_file.as_os_str().is_empty() {
+                return bare.into();
+            }
+        }
+    }
+    CommandLineOptions::parse_args_default_or_exit()
+}
+
 fn main() {
-    let opt = CommandLineOptions::parse_args_default_or_exit();
-    println!("{:?}", opt);
+    let opt = parse_options();
+
+    let border = opt.border.as_ref().map(|b| b.0).unwrap_or(Border::White);
+    match &opt.command {
+        // bench opens its own connector for every setting
+        Some(Command::Bench(bench_opt)) => bench(bench_opt, opt.verbose),
+        Some(Command::Replay(replay_opt)) if replay_opt.dump => dump(replay_opt),
+        Some(command) => {
+            let connector = open_connector(opt.verbose);
+            match &opt.record {
+                Some(path) => {
+                    // unbuffered, whatever was sent before a crash is on disk
+                    let file = File::create(path).unwrap_or_else(|e| {
+                        eprintln!("failed to create {}: {}", path.display(), e);
+                        process::exit(1)
+                    });
+                    // the V2 panel's GD7965 only updates BUSY after a status request
+                    let recorder = RecordingConnector::with_status_command(connector, file, gd7965::opcode::FLG)
+                        .expect("failed to start recording");
+                    run(command, recorder, opt.verbose, border)
+                }
+                None => run(command, connector, opt.verbose, border),
+            }
+        }
+        None => {
+            eprintln!("{}", CommandLineOptions::usage());
+            eprintln!("\nAvailable commands:\n{}", Command::usage());
+            eprintln!("\nA bare <image> without a command is shown like with show.");
+            process::exit(2);
+        }
+    }
+}
+
+fn run<C: DisplayConnector>(command: &Command, connector: C, verbose: bool, border: Border) {
+    match command {
+        Command::Show(show_opt) => show(show_opt, connector, verbose, border),
+        Command::Clean(clean_opt) => clean(clean_opt, connector, verbose, border),
+        Command::Check(_) => check(connector),
+        Command::Replay(replay_opt) => replay(replay_opt, connector),
+        Command::Bench(_) => unreachable!("bench opens its own connectors"),
+    }
+}
 
+fn show<C: DisplayConnector>(opt: &ShowOptions, connector: C, verbose: bool, border: Border) {
     // TODO: oether display
     // provide 'middle colour reference'
 
-    if opt.verbose {
+    if verbose {
         println!("initializing display")
     }
-    let mut display = init_display(opt.verbose);
-    if opt.verbose {
+    let mut display = init_display(connector, verbose, border);
+    if verbose {
         println!("init done display")
     }
 
@@ -86,7 +309,9 @@ fn main() {
     let display_ar = display.width() as f32 /display.height() as f32;
 
     let img_ar = im.width() as f32 / im.height() as f32;
-    print!("{}x{} vs {}x{} display_ar={} img_ar={}", display.width(), display.height(), im.width(), im.height(), display_ar, img_ar);
+    if verbose {
+        println!("{}x{} vs {}x{} display_ar={} img_ar={}", display.width(), display.height(), im.width(), im.height(), display_ar, img_ar);
+    }
     let crop = if img_ar > display_ar {
         let target_width =  (im.height() as f32 * display_ar) as u32;
         let x_off = match opt.crop_align {
@@ -94,7 +319,9 @@ fn main() {
             Option::Some(CropAlign::BottomRight) => im.width() - target_width,
             _ => (im.width() - target_width)/2
         };
-        println!("cropA {},{},{},{}",x_off, 0, target_width, im.height());
+        if verbose {
+            println!("cropA {},{},{},{}",x_off, 0, target_width, im.height());
+        }
         im.crop( x_off, 0, target_width, im.height())
     } else {
         let target_height=  (im.width() as f32 / display_ar) as u32;
@@ -103,14 +330,18 @@ fn main() {
             Option::Some(CropAlign::BottomRight) => im.height() - target_height,
             _ => (im.height() - target_height)/2
         };
-        println!("cropB {},{},{},{}",0,y_off, im.width(), target_height);
+        if verbose {
+            println!("cropB {},{},{},{}",0,y_off, im.width(), target_height);
+        }
         im.crop(0,y_off, im.width(), target_height)
         //  im.crop(0,0, im.width(), target_height)
     };
 
     let resized_im = crop.resize(display.width() as u32, display.height() as u32, FilterType::Lanczos3);
 
-    println!("sized: {}x{}", resized_im.width(), resized_im.height());
+    if verbose {
+        println!("sized: {}x{}", resized_im.width(), resized_im.height());
+    }
 
     let final_im = if opt.flipv {
         if opt.fliph { resized_im.flipv().fliph() } else { resized_im.flipv() }
@@ -118,7 +349,7 @@ fn main() {
         if opt.fliph { resized_im.fliph() } else { resized_im }
     };
 
-    let img = dither_image(final_im).unwrap();
+    let img = dither_image(final_im, verbose).unwrap();
 
     display.push_image_with(|x,y| {
         img.get((x,y)).map(|rgb| match rgb.0 { x if x < 85  => 0, x if x < 170 => 1, _ => 2 }).unwrap_or( 0)
@@ -126,54 +357,119 @@ fn main() {
     println!("all done");
 }
 
-fn init_display(verbose:bool) -> impl EPaperDisplay {
-    let mut spi = Spidev::open("/dev/spidev0.0").expect("failed to open spi device");
-    if verbose {
-        println!("spi open")
-    }
-
-    let options = SpidevOptions::new()
-        .bits_per_word(8)
-        .max_speed_hz(4_000_000)
-        .mode(SpiModeFlags::SPI_MODE_0)
-        .build();
-    spi.configure(&options).expect("spi configuration");
-    if verbose {
-        println!("spi done")
+fn clean<C: DisplayConnector>(opt: &CleanOptions, connector: C, verbose: bool, border: Border) {
+    let mut display = init_display(connector, verbose, border);
+    loop {
+        if verbose {
+            println!("deep clean started")
+        }
+        display.deep_clean().expect("could not clean display");
+        println!("deep clean done");
+        match opt.every {
+            Some(minutes) => thread::sleep(Duration::from_secs(minutes * 60)),
+            None => break
+        }
     }
+}
 
-    let mut gpio_chip = Chip::new("/dev/gpiochip0").expect("failed to open gpio device");
-
-    let reset_line_handle = gpio_chip.get_line(17).expect("failed to get reset line").request(LineRequestFlags::OUTPUT, 0,"epd reset write" ).unwrap();
-    if verbose {
-        println!("rst line handle done")
+fn check<C: DisplayConnector>(connector: C) {
+    let mut display = EPaper75TriColourV2::new(connector);
+    let report = display.self_test().expect("self test failed");
+    println!("busy line: {:?}", report.busy);
+    match report.revision {
+        Some(revision) => println!("revision: {:02x?}", revision),
+        None => println!("revision: not readable"),
     }
-    let dc_line_handle = gpio_chip.get_line(25).expect("failed to get dc line").request(LineRequestFlags::OUTPUT, 0,"epd dc write" ).unwrap();
-    if verbose {
-        println!("rst line handle done")
+    if report.present {
+        println!("display present");
+    } else {
+        eprintln!("display not responding, check the cable and power");
+        process::exit(1);
     }
-    let busy_line_handle = gpio_chip.get_line(24).expect("failed to get busy line").request(LineRequestFlags::INPUT, 0,"epd busy read" ).unwrap();
-    if verbose {
-        println!("busy line handle done")
+}
+
+// only fills the controller's frame memory, nothing is refreshed
+fn bench(opt: &BenchOptions, verbose: bool) {
+    let detected = LinuxConnectorBuilder::waveshare_hat().effective_chunk_size();
+    match spidev_bufsiz() {
+        Ok(bufsiz) => println!("spidev bufsiz: {} bytes", bufsiz),
+        Err(e) => println!("spidev bufsiz not readable ({}), assuming {} bytes", e, detected),
     }
-    let rst = CdevPin::new(reset_line_handle).unwrap();
-    rst.set_value(1).expect("rst set value failed");
-    if verbose {
-        println!("rst ready")
+    let chunk_sizes = match &opt.chunk_sizes {
+        Some(list) => list.0.clone(),
+        None if detected == 1024 => vec![1024],
+        None => vec![1024, detected as u32],
+    };
+
+    let frame = vec![0xffu8; 800 / 8 * 480];
+    for &speed in &opt.speeds.0 {
+        for &chunk_size in &chunk_sizes {
+            let builder = LinuxConnectorBuilder::waveshare_hat().speed_hz(speed).chunk_size(Some(chunk_size as usize));
+            if verbose {
+                println!("opening display {:?}", builder)
+            }
+            let mut controller = GD7965::new(builder.build().unwrap_or_else(|e| {
+                eprintln!("failed to open display: {}", e);
+                process::exit(1)
+            }));
+            controller.reset().expect("reset failed");
+
+            let started = Instant::now();
+            let result = (0..opt.frames).try_for_each(|_| controller.transmit(&frame));
+            let elapsed = started.elapsed().as_secs_f64();
+            match result {
+                Ok(()) => {
+                    let kbit = (frame.len() * 8) as f64 * opt.frames as f64 / elapsed / 1000.0;
+                    println!("{:>9} Hz {:>6} bytes: {:>7.1} ms/frame {:>7.0} kbit/s",
+                             speed, chunk_size, elapsed * 1000.0 / opt.frames.max(1) as f64, kbit)
+                }
+                Err(e) => println!("{:>9} Hz {:>6} bytes: failed with {:?}", speed, chunk_size, e),
+            }
+        }
     }
-    let dc = CdevPin::new(dc_line_handle).unwrap();
-    dc.set_value(1).expect("dc set value failed");
-    if verbose {
-        println!("dc ready")
+}
+
+fn replay<C: DisplayConnector>(opt: &ReplayOptions, mut connector: C) {
+    let recording = read_recording(&opt.recording);
+    match record::replay(&recording, &mut connector) {
+        Ok(events) => println!("replayed {} events", events),
+        Err(e) => {
+            eprintln!("replay failed: {:?}", e);
+            process::exit(1);
+        }
     }
-    let busy = CdevPin::new(busy_line_handle).unwrap();
-    if verbose {
-        println!("pins ready done")
+}
+
+fn dump(opt: &ReplayOptions) {
+    let recording = read_recording(&opt.recording);
+    let events = Events::new(&recording).unwrap_or_else(|e| {
+        eprintln!("not a recording: {:?}", e);
+        process::exit(1)
+    });
+    for event in events {
+        match event {
+            Ok(Event::Data(data)) => println!("data {} bytes {:02x?}", data.len(), &data[..data.len().min(8)]),
+            Ok(Event::Read(data)) => println!("read {:02x?}", data),
+            Ok(Event::Command(command)) => println!("command {:02x}", command),
+            Ok(event) => println!("{:?}", event),
+            Err(e) => {
+                eprintln!("recording is damaged: {:?}", e);
+                process::exit(1);
+            }
+        }
     }
+}
 
-    let connector = SpiConnector::new(spi, rst, dc, busy, Delay {}, 1024);
+fn read_recording(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path.display(), e);
+        process::exit(1)
+    })
+}
 
+fn init_display<C: DisplayConnector>(connector: C, verbose:bool, border: Border) -> impl EPaperDisplay {
     let mut display = EPaper75TriColourV2::new(connector);
+    display.set_border(border);
 
     display.init().expect("failed to init display");
 
@@ -183,26 +479,34 @@ fn init_display(verbose:bool) -> impl EPaperDisplay {
     display
 }
 
-fn dither_image(d_img : DynamicImage)  -> dither::Result<Img<RGB<u8>>> {
+fn open_connector(verbose: bool) -> LinuxConnector {
+    let builder = LinuxConnectorBuilder::waveshare_hat();
+    if verbose {
+        println!("opening display {:?}", builder)
+    }
+    builder.build().unwrap_or_else(|e| {
+        eprintln!("failed to open display: {}", e);
+        process::exit(1)
+    })
+}
+
+fn dither_image(d_img : DynamicImage, verbose: bool)  -> dither::Result<Img<RGB<u8>>> {
 
     let rgb_img = d_img.to_rgb8();
     let rgb_buffer: Vec<RGB<u8>> = rgb_img.pixels().map(|p| RGB::from(p.0)).collect();
 
     let img= Img::<RGB<u8>>::new(rgb_buffer, d_img.width()).expect("dither image instantiation").convert_with(|rgb| rgb.convert_with(f64::from));
 
-    //  TODO:  get this from the driver
-    let pallet = [
-        RGB(0x00,0x00,0x00),
-        //  RGB(0x80,0x5F,0x10),
-        RGB(0x93,0x78,0x00),
-        RGB(0xFF,0xFF, 0xFF)];
+    let pallet: Vec<RGB<u8>> = TRI_COLOUR_PALETTE.iter().map(|entry| RGB::from(entry.rgb)).collect();
 
 
     let dithered_img = ditherer::ATKINSON.dither(img, palette::quantize(&pallet)).convert_with(|rgb| rgb.convert_with(clamp_f64_to_u8));
     // .convert_with(|rgb| rgb.convert_with(clamp_f64_to_u8));
 
     // The dimensions method returns the images width and height
-    println!("dimensions {}x{} {:?} {}", dithered_img.width(), dithered_img.height(), dithered_img.size(), dithered_img.len());
+    if verbose {
+        println!("dimensions {}x{} {:?} {}", dithered_img.width(), dithered_img.height(), dithered_img.size(), dithered_img.len());
+    }
 
     return Result::Ok(dithered_img);
 }
@@ -0,0 +1,184 @@
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+
+use gumdrop::Options;
+
+use epdriver::display_connector::{DisplayConnector, Result};
+use epdriver::linux::LinuxConnectorBuilder;
+use epdriver::remote;
+
+// BUSY stays asserted for this many reads after power on or a refresh
+const SIMULATED_BUSY_READS: u32 = 3;
+
+#[derive(Debug)]
+enum Board {
+    Waveshare,
+    Inky,
+}
+
+impl FromStr for Board {
+    type Err = String;
+    fn from_str(board: &str) -> std::result::Result<Self, Self::Err> {
+        match board.to_lowercase().as_str() {
+            "waveshare"|"w" => Ok(Board::Waveshare),
+            "inky"|"i" => Ok(Board::Inky),
+            _ => Err(format!("failed to parse board from {}", board)),
+        }
+    }
+}
+
+#[derive(Debug, Options)]
+struct ServerOptions {
+
+    #[options(help = "print help message")]
+    help: bool,
+
+    #[options(help = "more information on stdout, the simulated panel prints every request")]
+    verbose: bool,
+
+    #[options(help = "TCP address to listen on", meta="ADDR", default = "127.0.0.1:7965")]
+    listen: String,
+
+    #[options(help = "listen on a Unix socket instead of TCP", meta="PATH")]
+    unix: Option<PathBuf>,
+
+    #[options(help = "serve a simulated panel instead of spidev and GPIO")]
+    simulate: bool,
+
+    #[options(help = "pin layout of the panel board", meta="[waveshare|w|inky|i]", default = "waveshare")]
+    board: Board,
+
+    #[options(help = "SPI clock in Hz", meta="HZ", default = "4000000")]
+    speed: u32,
+}
+
+/// Stands in for a panel: accepts everything, reports BUSY for a few reads
+/// after power on and refreshes and doesn't wait for delays.
+struct SimulatedConnector {
+    verbose: bool,
+    busy_reads: Cell<u32>,
+}
+
+impl DisplayConnector for SimulatedConnector {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
+        if self.verbose {
+            println!("reset {} ms", low_ms);
+        }
+        Ok(())
+    }
+
+    fn is_busy(&self) -> Result<bool> {
+        let reads = self.busy_reads.get();
+        self.busy_reads.set(reads.saturating_sub(1));
+        Ok(reads > 0)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<()> {
+        if self.verbose {
+            println!("command 0x{:02x}", command);
+        }
+        // PON and DRF
+        if command == 0x04 || command == 0x12 {
+            self.busy_reads.set(SIMULATED_BUSY_READS);
+        }
+        Ok(())
+    }
+
+    fn send_data_with<F>(&mut self, repeats: u32, _source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        if self.verbose {
+            println!("data {} bytes", repeats);
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        if self.verbose {
+            println!("data {:02x?}", &data[..data.len().min(8)]);
+        }
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, ms: u16) -> Result<()> {
+        if self.verbose {
+            println!("delay {} ms", ms);
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let opt = ServerOptions::parse_args_default_or_exit();
+
+    if opt.simulate {
+        let connector = SimulatedConnector { verbose: opt.verbose, busy_reads: Cell::new(0) };
+        listen(&opt, connector);
+    } else {
+        let builder = match opt.board {
+            Board::Waveshare => LinuxConnectorBuilder::waveshare_hat(),
+            Board::Inky => LinuxConnectorBuilder::pimoroni_inky(),
+        }.speed_hz(opt.speed);
+        if opt.verbose {
+            println!("opening display {:?}", builder);
+        }
+        let connector = builder.build().unwrap_or_else(|e| {
+            eprintln!("failed to open display: {}", e);
+            process::exit(1)
+        });
+        listen(&opt, connector);
+    }
+}
+
+fn listen<C: DisplayConnector>(opt: &ServerOptions, mut connector: C) {
+    let result = match &opt.unix {
+        Some(path) => {
+            // a socket left behind by an earlier run would make bind fail
+            if fs::metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+                let _ = fs::remove_file(path);
+            }
+            UnixListener::bind(path).map(|listener| {
+                println!("listening on {}", path.display());
+                serve_all(&mut connector, listener.incoming(), opt.verbose)
+            })
+        }
+        None => TcpListener::bind(&opt.listen).map(|listener| {
+            println!("listening on {}", opt.listen);
+            let incoming = listener.incoming().map(|stream| {
+                let stream = stream?;
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            });
+            serve_all(&mut connector, incoming, opt.verbose)
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("failed to listen: {}", e);
+        process::exit(1);
+    }
+}
+
+// one client at a time, the panel can't be shared anyway
+fn serve_all<C, S, I>(connector: &mut C, incoming: I, verbose: bool) where C: DisplayConnector, S: Read + Write, I: Iterator<Item = io::Result<S>> {
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept a client: {}", e);
+                continue;
+            }
+        };
+        if verbose {
+            println!("client connected");
+        }
+        match remote::serve(connector, stream) {
+            Ok(requests) => println!("client done after {} requests", requests),
+            Err(e) => eprintln!("client dropped: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Runs init and a full frame on both panels against a simulated controller
+// and prints how much time went into delays compared to the part of it
+// the controller actually kept BUSY asserted. Nothing sleeps, the simulated
+// clock only moves when the driver asks for a delay.
+//
+//     cargo run --example delay_budget
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use epdriver::display_connector::SpiConnector;
+use epdriver::{EPaper75TriColour, EPaper75TriColourV2, EPaperDisplay};
+
+// rough busy times of a tri-colour panel
+const POWER_ON_MS: u32 = 40;
+const POWER_OFF_MS: u32 = 20;
+const REFRESH_MS: u32 = 16_000;
+
+#[derive(Default)]
+struct Controller {
+    clock_ms: u32,
+    busy_until: u32,
+    busy_ms: u32,
+    delay_ms: u32,
+    data_mode: bool,
+}
+
+impl Controller {
+    fn command(&mut self, command: u8) {
+        let busy = match command {
+            0x02 => POWER_OFF_MS,
+            0x04 => POWER_ON_MS,
+            0x12 => REFRESH_MS,
+            _ => return,
+        };
+        self.busy_until = self.clock_ms + busy;
+    }
+}
+
+type Shared = Rc<RefCell<Controller>>;
+
+struct Spi(Shared);
+
+impl Write<u8> for Spi {
+    type Error = ();
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let mut controller = self.0.borrow_mut();
+        if !controller.data_mode {
+            words.iter().for_each(|&command| controller.command(command));
+        }
+        Ok(())
+    }
+}
+
+impl Transfer<u8> for Spi {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        Ok(words)
+    }
+}
+
+// drives DC when `dc` is set, RST is ignored
+struct Pin {
+    controller: Shared,
+    dc: bool,
+}
+
+impl OutputPin for Pin {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        if self.dc {
+            self.controller.borrow_mut().data_mode = false;
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        if self.dc {
+            self.controller.borrow_mut().data_mode = true;
+        }
+        Ok(())
+    }
+}
+
+struct Busy(Shared);
+
+impl InputPin for Busy {
+    type Error = ();
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.is_low().map(|low| !low)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        let controller = self.0.borrow();
+        Ok(controller.clock_ms < controller.busy_until)
+    }
+}
+
+struct Delay(Shared);
+
+impl DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        let mut controller = self.0.borrow_mut();
+        // only the part of the delay the controller was actually busy for
+        let busy = controller.busy_until.saturating_sub(controller.clock_ms).min(ms as u32);
+        controller.busy_ms += busy;
+        controller.clock_ms += ms as u32;
+        controller.delay_ms += ms as u32;
+    }
+}
+
+fn connector(controller: &Shared) -> SpiConnector<Spi, Pin, Busy, Delay> {
+    let rst = Pin { controller: controller.clone(), dc: false };
+    let dc = Pin { controller: controller.clone(), dc: true };
+    SpiConnector::new(Spi(controller.clone()), rst, dc, Busy(controller.clone()), Delay(controller.clone()), 4096)
+}
+
+fn report<D: EPaperDisplay>(name: &str, controller: &Shared, mut display: D) {
+    display.init().expect("init");
+    display.push_image_with(|x, y| ((x + y) % 3) as u8).expect("push");
+    let controller = controller.borrow();
+    println!("{}: {} ms in delays, {} ms busy, {} ms overhead",
+             name, controller.delay_ms, controller.busy_ms, controller.delay_ms.saturating_sub(controller.busy_ms));
+}
+
+fn main() {
+    let controller = Shared::default();
+    report("7.5\" V1 (IL0371)", &controller, EPaper75TriColour::new(connector(&controller)));
+
+    let controller = Shared::default();
+    report("7.5\" V2 (GD7965)", &controller, EPaper75TriColourV2::new(connector(&controller)));
+}
@@ -0,0 +1,186 @@
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use core::result;
+
+use crate::controller::display_connector::{DisplayConnector, Error, NoChipSelect, Result, SpiConnector};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitBangError {
+    PinWriteError,
+    /// there is no MISO, the bus can only write
+    ReadUnsupported,
+}
+
+/// Write only SPI mode 0 on two GPIOs, MSB first. CLK idles low and the
+/// controller samples MOSI on the rising edge.
+///
+/// Every half clock period waits `half_period_us`, 0 skips the delay for
+/// GPIOs that are slow enough on their own.
+pub struct BitBangSpi<CLK, MOSI, DELAY> where CLK: OutputPin, MOSI: OutputPin, DELAY: DelayUs<u16> {
+    clk: CLK,
+    mosi: MOSI,
+    delay: DELAY,
+    half_period_us: u16,
+}
+
+impl<CLK, MOSI, DELAY> BitBangSpi<CLK, MOSI, DELAY> where CLK: OutputPin, MOSI: OutputPin, DELAY: DelayUs<u16> {
+    pub fn new(mut clk: CLK, mosi: MOSI, delay: DELAY, half_period_us: u16) -> Result<BitBangSpi<CLK, MOSI, DELAY>> {
+        clk.set_low().map_err(|_e| Error::SpiWriteError)?;
+        Ok(BitBangSpi { clk, mosi, delay, half_period_us })
+    }
+
+    fn half_period(&mut self) {
+        if self.half_period_us > 0 {
+            self.delay.delay_us(self.half_period_us);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> result::Result<(), BitBangError> {
+        for bit in (0..8).rev() {
+            let pin = if byte >> bit & 1 == 1 { self.mosi.set_high() } else { self.mosi.set_low() };
+            pin.map_err(|_e| BitBangError::PinWriteError)?;
+            self.half_period();
+            self.clk.set_high().map_err(|_e| BitBangError::PinWriteError)?;
+            self.half_period();
+            self.clk.set_low().map_err(|_e| BitBangError::PinWriteError)?;
+        }
+        Ok(())
+    }
+}
+
+impl<CLK, MOSI, DELAY> Write<u8> for BitBangSpi<CLK, MOSI, DELAY> where CLK: OutputPin, MOSI: OutputPin, DELAY: DelayUs<u16> {
+    type Error = BitBangError;
+
+    fn write(&mut self, words: &[u8]) -> result::Result<(), Self::Error> {
+        words.iter().try_for_each(|&byte| self.write_byte(byte))
+    }
+}
+
+impl<CLK, MOSI, DELAY> Transfer<u8> for BitBangSpi<CLK, MOSI, DELAY> where CLK: OutputPin, MOSI: OutputPin, DELAY: DelayUs<u16> {
+    type Error = BitBangError;
+
+    fn transfer<'w>(&mut self, _words: &'w mut [u8]) -> result::Result<&'w [u8], Self::Error> {
+        Err(BitBangError::ReadUnsupported)
+    }
+}
+
+/// `SpiConnector` on a `BitBangSpi`, for boards that route the panel to
+/// plain GPIOs. RST, DC, BUSY and CS behave exactly like on `SpiConnector`,
+/// reading isn't supported.
+pub struct BitBangConnector<CLK, MOSI, OUT, IN, DELAY, BITDELAY, CS = NoChipSelect>
+    where CLK: OutputPin, MOSI: OutputPin, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, BITDELAY: DelayUs<u16>, CS: OutputPin {
+    connector: SpiConnector<BitBangSpi<CLK, MOSI, BITDELAY>, OUT, IN, DELAY, CS>,
+}
+
+impl<CLK, MOSI, OUT, IN, DELAY, BITDELAY> BitBangConnector<CLK, MOSI, OUT, IN, DELAY, BITDELAY>
+    where CLK: OutputPin, MOSI: OutputPin, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, BITDELAY: DelayUs<u16> {
+    pub fn new(spi: BitBangSpi<CLK, MOSI, BITDELAY>, rst: OUT, dc: OUT, busy: IN, delay: DELAY) -> BitBangConnector<CLK, MOSI, OUT, IN, DELAY, BITDELAY> {
+        // no transfer size limit when the bytes are clocked out by hand
+        BitBangConnector { connector: SpiConnector::new(spi, rst, dc, busy, delay, usize::MAX) }
+    }
+
+    /// See `SpiConnector::with_chip_select`.
+    pub fn with_chip_select<CS>(self, cs: CS) -> Result<BitBangConnector<CLK, MOSI, OUT, IN, DELAY, BITDELAY, CS>> where CS: OutputPin {
+        Ok(BitBangConnector { connector: self.connector.with_chip_select(cs)? })
+    }
+}
+
+impl<CLK, MOSI, OUT, IN, DELAY, BITDELAY, CS> DisplayConnector for BitBangConnector<CLK, MOSI, OUT, IN, DELAY, BITDELAY, CS>
+    where CLK: OutputPin, MOSI: OutputPin, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, BITDELAY: DelayUs<u16>, CS: OutputPin {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
+        self.connector.reset(low_ms)
+    }
+
+    fn is_busy(&self) -> Result<bool> {
+        self.connector.is_busy()
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<()> {
+        self.connector.send_command(command)
+    }
+
+    fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        self.connector.send_data_with(repeats, source)
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.connector.send_data(data)
+    }
+
+    fn delay_ms(&mut self, ms: u16) -> Result<()> {
+        self.connector.delay_ms(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use core::cell::RefCell;
+    use crate::controller::bit_bang::{BitBangConnector, BitBangSpi};
+    use crate::controller::display_connector::{DisplayConnector, Error};
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::controller::gd7965::GD7965;
+    use crate::test_kit::{LoggingPin, MockDelay, MockPin};
+
+    // replays the pin log like the controller would see it, returns the
+    // bytes clocked in with the DC level at their last bit
+    fn decode(log: &[(&'static str, bool)]) -> Vec<(bool, u8)> {
+        let (mut mosi, mut dc, mut clk) = (false, false, false);
+        let mut bits = 0;
+        let mut byte = 0u8;
+        let mut bytes = Vec::new();
+        for &(name, level) in log {
+            match name {
+                "mosi" => mosi = level,
+                "dc" => dc = level,
+                "clk" => {
+                    if level && !clk {
+                        byte = byte << 1 | mosi as u8;
+                        bits += 1;
+                        if bits == 8 {
+                            bytes.push((dc, byte));
+                            bits = 0;
+                        }
+                    }
+                    clk = level;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(bits, 0, "incomplete byte");
+        bytes
+    }
+
+    fn connector<'a>(log: &'a RefCell<Vec<(&'static str, bool)>>) -> BitBangConnector<LoggingPin<'a>, LoggingPin<'a>, LoggingPin<'a>, MockPin, MockDelay, MockDelay> {
+        let spi = BitBangSpi::new(LoggingPin { name: "clk", log }, LoggingPin { name: "mosi", log }, MockDelay {}, 1).unwrap();
+        let rst = LoggingPin { name: "rst", log };
+        let dc = LoggingPin { name: "dc", log };
+        BitBangConnector::new(spi, rst, dc, MockPin { name: "busy", state: true }, MockDelay {})
+    }
+
+    #[test]
+    fn test_bit_bang_bytes() {
+        let log = RefCell::new(Vec::new());
+        let mut connector = connector(&log);
+        connector.send_command(0x10).unwrap();
+        connector.send_data(&[0xa5, 0x01]).unwrap();
+        connector.send_data_with(2, |i| 0x80 >> i).unwrap();
+
+        assert_eq!(log.borrow()[0], ("clk", false));
+        assert_eq!(decode(&log.borrow()), [(false, 0x10), (true, 0xa5), (true, 0x01), (true, 0x80), (true, 0x40)]);
+        // mode 0, CLK idles low
+        assert_eq!(log.borrow().iter().rev().find(|(name, _)| *name == "clk"), Some(&("clk", false)));
+    }
+
+    #[test]
+    fn test_bit_bang_controller() {
+        let log = RefCell::new(Vec::new());
+        let mut controller = GD7965::new(connector(&log));
+        controller.tres_resolution(800, 480).unwrap();
+
+        assert_eq!(decode(&log.borrow()), [(false, 0x61), (true, 0x03), (true, 0x20), (true, 0x01), (true, 0xe0)]);
+        assert!(matches!(controller.connector().read_data(&mut [0; 3]), Err(Error::ReadUnsupported)));
+    }
+}
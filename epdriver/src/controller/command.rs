@@ -0,0 +1,83 @@
+use core::result;
+
+use crate::controller::display_connector::{DisplayConnector, Result};
+use crate::controller::settings::ConfigError;
+
+// longest parameter list that isn't pixel or LUT data (GD7965 PTL)
+const MAX_INLINE_PARAMS: usize = 9;
+
+/// Returned when captured bytes don't form a valid command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// wrong number of parameter bytes for the opcode
+    Length,
+    /// a parameter is outside the range the controller accepts
+    Value(ConfigError),
+}
+
+impl From<ConfigError> for DecodeError {
+    fn from(e: ConfigError) -> Self {
+        DecodeError::Value(e)
+    }
+}
+
+pub type DecodeResult<T> = result::Result<T, DecodeError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Params<'a> {
+    Inline([u8; MAX_INLINE_PARAMS], usize),
+    Borrowed(&'a [u8]),
+}
+
+/// Opcode and parameter bytes of an encoded command, pixel and LUT data is
+/// borrowed rather than copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoded<'a> {
+    opcode: u8,
+    params: Params<'a>,
+}
+
+impl<'a> Encoded<'a> {
+    pub(crate) fn new(opcode: u8, params: &[u8]) -> Encoded<'a> {
+        let mut inline = [0; MAX_INLINE_PARAMS];
+        inline[..params.len()].copy_from_slice(params);
+        Encoded { opcode, params: Params::Inline(inline, params.len()) }
+    }
+
+    pub(crate) fn borrowed(opcode: u8, data: &'a [u8]) -> Encoded<'a> {
+        Encoded { opcode, params: Params::Borrowed(data) }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn params(&self) -> &[u8] {
+        match &self.params {
+            Params::Inline(bytes, len) => &bytes[..*len],
+            Params::Borrowed(data) => data,
+        }
+    }
+
+    pub(crate) fn send<C>(&self, connector: &mut C) -> Result<()> where C: DisplayConnector {
+        connector.send_command(self.opcode)?;
+        if self.params().is_empty() {
+            return Ok(());
+        }
+        connector.send_data(self.params())
+    }
+}
+
+// parameter bytes of a fixed length command
+pub(crate) fn expect_params<const N: usize>(data: &[u8]) -> DecodeResult<[u8; N]> {
+    if data.len() != N {
+        return Err(DecodeError::Length);
+    }
+    let mut params = [0; N];
+    params.copy_from_slice(data);
+    Ok(params)
+}
+
+pub(crate) fn be_u16(high: u8, low: u8) -> u16 {
+    (high as u16) << 8 | low as u16
+}
@@ -3,75 +3,205 @@ use embedded_hal::digital::v2::{OutputPin, InputPin};
 use embedded_hal::blocking::delay::DelayMs;
 
 
+use core::cell::RefCell;
+use core::convert::Infallible;
 use core::result;
 
-const TMP_BUFFER_SIZE: usize = 320 * 384;
+use crate::controller::settings::ConfigError;
+
+/// Default size of the stack buffer `send_data_with` stages bytes in.
+pub const DEFAULT_STAGING_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub enum Error {
     SpiWriteError,
+    SpiReadError,
+    /// the connector can't read from the controller
+    ReadUnsupported,
+    /// BUSY didn't clear within the controller's timeout
+    BusyTimeout,
     DelayError,
     BusyPinReadError,
     // BusyPinWriteError
     // PinReadError,
     ResetPinWriteError,
-    DcPinWriteError
+    DcPinWriteError,
+    CsPinWriteError,
+    BufferSizeError,
+    ConfigError(ConfigError),
+    /// the sink of a recording failed or is full
+    RecordWriteError,
+    /// a recording to replay is malformed or of another version
+    RecordFormatError,
+    /// the connection to the other end of a remote connector failed or it
+    /// sent something unexpected
+    RemoteError,
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::ConfigError(e)
+    }
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 pub trait DisplayConnector {
-    fn reset(&mut self) -> Result<()>;
+    /// Pulses RST low for `low_ms`, waiting for the controller afterwards
+    /// is up to the caller.
+    fn reset(&mut self, low_ms: u16) -> Result<()>;
     fn is_busy(&self) -> Result<bool>;
     fn send_command(&mut self, command: u8) -> Result<()>;
     fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8;
     fn send_data(&mut self, data: &[u8]) -> Result<()>;
     fn delay_ms(&mut self, ms: u16) -> Result<()>;
+
+    /// Reads data bytes after a command, only possible when the data line
+    /// can be read back.
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let _ = buffer;
+        Err(Error::ReadUnsupported)
+    }
+}
+
+/// Chip select of a connector that has the bus to itself, or whose SPI
+/// device drives CS on its own like spidev does.
+pub struct NoChipSelect;
+
+impl OutputPin for NoChipSelect {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> result::Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Lets several connectors use one SPI bus, each of them needs its own
+/// chip select then.
+///
+/// ```ignore
+/// let bus = RefCell::new(spi);
+/// let left = SpiConnector::new(SharedBus::new(&bus), rst0, dc0, busy0, delay0, 4096).with_chip_select(cs0)?;
+/// let right = SpiConnector::new(SharedBus::new(&bus), rst1, dc1, busy1, delay1, 4096).with_chip_select(cs1)?;
+/// ```
+pub struct SharedBus<'a, SPI> {
+    bus: &'a RefCell<SPI>,
+}
+
+impl<'a, SPI> SharedBus<'a, SPI> {
+    pub fn new(bus: &'a RefCell<SPI>) -> SharedBus<'a, SPI> {
+        SharedBus { bus }
+    }
+}
+
+impl<SPI> Write<u8> for SharedBus<'_, SPI> where SPI: Write<u8> {
+    type Error = SPI::Error;
+
+    fn write(&mut self, words: &[u8]) -> result::Result<(), Self::Error> {
+        self.bus.borrow_mut().write(words)
+    }
+}
+
+impl<SPI> Transfer<u8> for SharedBus<'_, SPI> where SPI: Transfer<u8> {
+    type Error = SPI::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> result::Result<&'w [u8], Self::Error> {
+        self.bus.borrow_mut().transfer(words)
+    }
 }
 
-//cat /sys/module/spidev/parameters/bufsiz
-pub struct SpiConnector<SPI, OUT, IN, DELAY> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16> {
+/// `chunk_size` is the longest single SPI write, on Linux it can't be more
+/// than spidev's `bufsiz`, which `LinuxConnectorBuilder` reads by itself.
+///
+/// `STAGING` is the size of the buffer `send_data_with` fills on the stack
+/// before handing it to the SPI bus, it only lives for the duration of the call
+/// and has to be non-zero, which is checked at compile time.
+///
+/// `CS` is driven low for every command and block of data, see
+/// `with_chip_select`.
+pub struct SpiConnector<SPI, OUT, IN, DELAY, CS = NoChipSelect, const STAGING: usize = DEFAULT_STAGING_SIZE> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, CS: OutputPin {
     spi: SPI,
     rst: OUT,
     dc: OUT,
     busy: IN,
     delay: DELAY,
+    cs: CS,
     chunk_size: usize,
-    tmp_buffer: Option<[u8; TMP_BUFFER_SIZE]>,
 }
 
-
 impl<SPI, OUT, IN, DELAY> SpiConnector<SPI, OUT, IN, DELAY> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16> {
     pub fn new(spi: SPI, rst: OUT, dc: OUT, busy: IN, delay: DELAY, chunk_size: usize) -> SpiConnector<SPI, OUT, IN, DELAY> {
+        Self::with_staging(spi, rst, dc, busy, delay, chunk_size)
+    }
+}
+
+impl<SPI, OUT, IN, DELAY, const STAGING: usize> SpiConnector<SPI, OUT, IN, DELAY, NoChipSelect, STAGING> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16> {
+    // evaluated when with_staging is instantiated, send_data_with indexes
+    // into the staging buffer
+    const STAGING_CHECK: () = assert!(STAGING > 0, "STAGING has to be non-zero");
+
+    /// Like `new` but with a custom staging buffer size, e.g.
+    /// `SpiConnector::<_, _, _, _, _, 64>::with_staging(..)` on RAM constrained targets.
+    pub fn with_staging(spi: SPI, rst: OUT, dc: OUT, busy: IN, delay: DELAY, chunk_size: usize) -> SpiConnector<SPI, OUT, IN, DELAY, NoChipSelect, STAGING> {
+        let () = Self::STAGING_CHECK;
         SpiConnector {
             spi,
             rst,
             dc,
             busy,
             delay,
+            cs: NoChipSelect,
             chunk_size, // depends on systems
-            tmp_buffer: Option::None,
         }
     }
 
+    /// Selects the panel with `cs` (active low) instead of relying on the
+    /// SPI device, needed when several panels share a bus. `cs` is driven
+    /// high straight away.
+    pub fn with_chip_select<CS>(self, mut cs: CS) -> Result<SpiConnector<SPI, OUT, IN, DELAY, CS, STAGING>> where CS: OutputPin {
+        cs.set_high().map_err(|_e| Error::CsPinWriteError)?;
+        Ok(SpiConnector {
+            spi: self.spi,
+            rst: self.rst,
+            dc: self.dc,
+            busy: self.busy,
+            delay: self.delay,
+            cs,
+            chunk_size: self.chunk_size,
+        })
+    }
+}
 
+impl<SPI, OUT, IN, DELAY, CS, const STAGING: usize> SpiConnector<SPI, OUT, IN, DELAY, CS, STAGING> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, CS: OutputPin {
     fn write(&mut self, data: &[u8]) -> Result<()> {
-        for data_chunk in data.chunks(self.chunk_size) {
-            self.spi.write(data_chunk).map_err(|_e| Error::SpiWriteError)?;
-        };
-        Result::Ok(())
+        self.selected(|connector| {
+            for data_chunk in data.chunks(connector.chunk_size) {
+                connector.spi.write(data_chunk).map_err(|_e| Error::SpiWriteError)?;
+            };
+            Result::Ok(())
+        })
+    }
+
+    // runs a transfer with CS low, CS goes high again even if it failed
+    fn selected<R, F>(&mut self, transfer: F) -> Result<R> where F: FnOnce(&mut Self) -> Result<R> {
+        self.cs.set_low().map_err(|_e| Error::CsPinWriteError)?;
+        let result = transfer(self);
+        let deselected = self.cs.set_high().map_err(|_e| Error::CsPinWriteError);
+        let value = result?;
+        deselected?;
+        Ok(value)
     }
 }
 
-impl<SPI, OUT, IN, DELAY> DisplayConnector for SpiConnector<SPI, OUT, IN, DELAY> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16> {
-    fn reset(&mut self) -> Result<()> {
-        self.rst.set_high().map_err(|_e| Error::ResetPinWriteError)?;
-        self.delay.delay_ms(200);
+impl<SPI, OUT, IN, DELAY, CS, const STAGING: usize> DisplayConnector for SpiConnector<SPI, OUT, IN, DELAY, CS, STAGING> where SPI: Write<u8> + Transfer<u8>, OUT: OutputPin, IN: InputPin, DELAY: DelayMs<u16>, CS: OutputPin {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
         self.rst.set_low().map_err(|_e| Error::ResetPinWriteError)?;
-        self.delay.delay_ms(4);
-        let r = self.rst.set_high().map_err(|_e| Error::ResetPinWriteError);
-        self.delay.delay_ms(200);
-        r
+        self.delay.delay_ms(low_ms);
+        self.rst.set_high().map_err(|_e| Error::ResetPinWriteError)
     }
 
     fn is_busy(&self) -> Result<bool> {
@@ -80,24 +210,17 @@ impl<SPI, OUT, IN, DELAY> DisplayConnector for SpiConnector<SPI, OUT, IN, DELAY>
 
     fn send_command(&mut self, command: u8) -> Result<()> {
         self.dc.set_low().map_err(|_e| Error::DcPinWriteError)?;
-        self.write(&[command])?;
-        self.delay.delay_ms(4);
-        Ok(())
+        self.write(&[command])
     }
 
     fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
-        let mut buffer = self.tmp_buffer.take().unwrap_or_else(|| {
-            [0; TMP_BUFFER_SIZE]
-        });
+        let mut buffer = [0; STAGING];
 
-        let buffer_size = buffer.len();
         let mut i = 0;
         for x in 0..repeats {
-            {
-                buffer[i] = source(x);
-            }
+            buffer[i] = source(x);
             i += 1;
-            if i % buffer_size == 0 {
+            if i == STAGING {
                 self.send_data(&buffer)?;
                 i = 0;
             }
@@ -105,9 +228,6 @@ impl<SPI, OUT, IN, DELAY> DisplayConnector for SpiConnector<SPI, OUT, IN, DELAY>
         if i > 0 {
             self.send_data(&buffer[0..i])?;
         }
-        self.delay.delay_ms(4);
-        //  self.send_command(0x11);
-        self.tmp_buffer = Some(buffer);
         Result::Ok(())
     }
 
@@ -121,4 +241,188 @@ impl<SPI, OUT, IN, DELAY> DisplayConnector for SpiConnector<SPI, OUT, IN, DELAY>
         self.delay.delay_ms(ms);
         Ok(())
     }
+
+    // needs MISO to be wired up, boards sharing a single data line read
+    // back zeros or whatever the line floats to
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.dc.set_high().map_err(|_e| Error::DcPinWriteError)?;
+        buffer.iter_mut().for_each(|b| *b = 0);
+        self.selected(|connector| {
+            connector.spi.transfer(buffer).map_err(|_e| Error::SpiReadError)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use core::cell::{Cell, RefCell};
+    use embedded_hal::blocking::spi::{Write, Transfer};
+    use embedded_hal::blocking::delay::DelayMs;
+    use embedded_hal::digital::v2::{InputPin, OutputPin};
+    use crate::controller::display_connector::{DisplayConnector, SharedBus, SpiConnector};
+    use crate::display::EPaperDisplay;
+    use crate::{EPaper75TriColour, EPaper75TriColourV2};
+
+    struct NoPin;
+
+    impl OutputPin for NoPin {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl InputPin for NoPin {
+        type Error = ();
+
+        fn is_high(&self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        fn is_low(&self) -> Result<bool, Self::Error> {
+            Ok(false)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayMs<u16> for NoDelay {
+        fn delay_ms(&mut self, _ms: u16) {}
+    }
+
+    // adds up the requested delays instead of sleeping
+    struct DelaySum<'a>(&'a Cell<u32>);
+
+    impl DelayMs<u16> for DelaySum<'_> {
+        fn delay_ms(&mut self, ms: u16) {
+            self.0.set(self.0.get() + ms as u32);
+        }
+    }
+
+    #[derive(Default)]
+    struct WriteRecorder {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Write<u8> for WriteRecorder {
+        type Error = ();
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            self.writes.push(words.to_vec());
+            Ok(())
+        }
+    }
+
+    impl Transfer<u8> for WriteRecorder {
+        type Error = ();
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+            Ok(words)
+        }
+    }
+
+    #[test]
+    fn test_send_data_with_staging() {
+        let mut connector = SpiConnector::<_, _, _, _, _, 4>::with_staging(WriteRecorder::default(), NoPin, NoPin, NoPin, NoDelay, 3);
+
+        connector.send_data_with(10, |i| i as u8).unwrap();
+
+        let writes = &connector.spi.writes;
+        assert_eq!(writes.len(), 5);
+        assert_eq!(writes[0], [0, 1, 2]);
+        assert_eq!(writes[1], [3]);
+        assert_eq!(writes[2], [4, 5, 6]);
+        assert_eq!(writes[3], [7]);
+        assert_eq!(writes[4], [8, 9]);
+    }
+
+    // with BUSY never asserted only the reset pulse should cost time,
+    // see examples/delay_budget.rs for a simulated panel
+    #[test]
+    fn test_delay_budget() {
+        let total = Cell::new(0);
+        let connector = SpiConnector::new(WriteRecorder::default(), NoPin, NoPin, NoPin, DelaySum(&total), 4096);
+        let mut v2 = EPaper75TriColourV2::new(connector);
+        v2.init().unwrap();
+        v2.push_image_with(|x, _| (x % 3) as u8).unwrap();
+        assert_eq!(total.get(), 12);
+
+        let total = Cell::new(0);
+        let connector = SpiConnector::new(WriteRecorder::default(), NoPin, NoPin, NoPin, DelaySum(&total), 4096);
+        let mut v1 = EPaper75TriColour::new(connector);
+        v1.init().unwrap();
+        v1.push_image_with(|x, _| (x % 3) as u8).unwrap();
+        assert_eq!(total.get(), 20);
+    }
+
+    // logs chip select edges into the same list as the bus writes
+    struct LoggedPin<'a> {
+        name: &'static str,
+        log: &'a RefCell<Vec<(&'static str, Vec<u8>)>>,
+    }
+
+    impl OutputPin for LoggedPin<'_> {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.log.borrow_mut().push((self.name, std::vec![0]));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.log.borrow_mut().push((self.name, std::vec![1]));
+            Ok(())
+        }
+    }
+
+    struct LoggedBus<'a>(&'a RefCell<Vec<(&'static str, Vec<u8>)>>);
+
+    impl Write<u8> for LoggedBus<'_> {
+        type Error = ();
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            self.0.borrow_mut().push(("spi", words.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl Transfer<u8> for LoggedBus<'_> {
+        type Error = ();
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+            Ok(words)
+        }
+    }
+
+    #[test]
+    fn test_shared_bus_chip_select() {
+        let log = RefCell::new(Vec::new());
+        let bus = RefCell::new(LoggedBus(&log));
+        let mut left = SpiConnector::new(SharedBus::new(&bus), NoPin, NoPin, NoPin, NoDelay, 2)
+            .with_chip_select(LoggedPin { name: "cs0", log: &log }).unwrap();
+        let mut right = SpiConnector::new(SharedBus::new(&bus), NoPin, NoPin, NoPin, NoDelay, 2)
+            .with_chip_select(LoggedPin { name: "cs1", log: &log }).unwrap();
+
+        left.send_command(0x10).unwrap();
+        right.send_data(&[1, 2, 3]).unwrap();
+
+        assert_eq!(*log.borrow(), [
+            ("cs0", std::vec![1]),
+            ("cs1", std::vec![1]),
+            ("cs0", std::vec![0]),
+            ("spi", std::vec![0x10]),
+            ("cs0", std::vec![1]),
+            ("cs1", std::vec![0]),
+            ("spi", std::vec![1, 2]),
+            ("spi", std::vec![3]),
+            ("cs1", std::vec![1]),
+        ]);
+    }
 }
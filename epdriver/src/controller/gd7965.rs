@@ -1,4 +1,7 @@
+use crate::controller::command::{be_u16, expect_params, DecodeResult, Encoded};
 use crate::controller::display_connector::{DisplayConnector, Result};
+use crate::controller::uc_family::{SharedCommand, UcFamilyController};
+use crate::controller::settings::{check_range, ConfigError, ConfigResult, DataInterval, NonOverlap};
 
 // driver
 
@@ -35,171 +38,643 @@ bitflags! {
     }
 }
 
-pub(crate) struct GD7965<T> where T: DisplayConnector {
-    connector: T
+/// VDH or VDHR level, 2.4V to 15V in 0.2V steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceVoltage(pub(crate) u8);
+
+impl SourceVoltage {
+    pub fn from_volts(volts: f32) -> ConfigResult<SourceVoltage> {
+        if !(2.4..=15.0).contains(&volts) {
+            return Err(ConfigError::VoltageOutOfRange);
+        }
+        Ok(SourceVoltage((0.5 + (volts - 2.4) / 0.2) as u8))
+    }
+
+    pub fn from_level(level: u8) -> ConfigResult<SourceVoltage> {
+        check_range(level, 0x3f, ConfigError::VoltageOutOfRange).map(SourceVoltage)
+    }
+
+    pub fn level(&self) -> u8 {
+        self.0
+    }
+}
+
+/// VDL level, -2.4V to -15V in 0.2V steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeSourceVoltage(pub(crate) u8);
+
+impl NegativeSourceVoltage {
+    pub fn from_volts(volts: f32) -> ConfigResult<NegativeSourceVoltage> {
+        if !(-15.0..=-2.4).contains(&volts) {
+            return Err(ConfigError::VoltageOutOfRange);
+        }
+        Ok(NegativeSourceVoltage((0.5 + (-volts - 2.4) / 0.2) as u8))
+    }
+
+    pub fn from_level(level: u8) -> ConfigResult<NegativeSourceVoltage> {
+        check_range(level, 0x3f, ConfigError::VoltageOutOfRange).map(NegativeSourceVoltage)
+    }
+
+    pub fn level(&self) -> u8 {
+        self.0
+    }
+}
+
+/// PLL frame rate code, from 1 (5Hz) to 15 (150Hz), 6 is 50Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate(pub(crate) u8);
+
+impl FrameRate {
+    pub fn from_code(code: u8) -> ConfigResult<FrameRate> {
+        if code == 0 {
+            return Err(ConfigError::FrameRateOutOfRange);
+        }
+        check_range(code, 0xf, ConfigError::FrameRateOutOfRange).map(FrameRate)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+/// VCOM DC level code, from 0 (-0.1V) to 0x4f (-4.05V) in 0.05V steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcomLevel(pub(crate) u8);
+
+impl VcomLevel {
+    pub fn from_code(code: u8) -> ConfigResult<VcomLevel> {
+        check_range(code, 0x4f, ConfigError::VoltageOutOfRange).map(VcomLevel)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+/// Frames between power off and the source drivers being switched off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOffDelay {
+    Frames1 = 0,
+    Frames2 = 1,
+    Frames3 = 2,
+    Frames4 = 3,
+}
+
+/// Power setting register (PWR).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerSetting {
+    pub flags: PWRFlags,
+    pub vdh: SourceVoltage,
+    pub vdl: NegativeSourceVoltage,
+    pub vdhr: SourceVoltage,
+}
+
+/// Booster soft start (BTST), phase C2 only has seven bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoosterSoftStart {
+    pha: u8,
+    phb: u8,
+    phc: u8,
+    phc2en: bool,
+    phc2: u8,
+}
+
+impl BoosterSoftStart {
+    pub fn new(pha: u8, phb: u8, phc: u8, phc2en: bool, phc2: u8) -> ConfigResult<BoosterSoftStart> {
+        check_range(phc2, 0x7f, ConfigError::ValueOutOfRange)?;
+        Ok(BoosterSoftStart { pha, phb, phc, phc2en, phc2 })
+    }
+
+    /// Phases A, B and C.
+    pub fn phases(&self) -> [u8; 3] {
+        [self.pha, self.phb, self.phc]
+    }
+
+    /// Phase C2, `None` unless it is enabled.
+    pub fn phc2(&self) -> Option<u8> {
+        if self.phc2en { Some(self.phc2) } else { None }
+    }
+}
+
+/// VCOM and data interval setting register (CDI).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataIntervalSetting {
+    pub bdz: bool,
+    pub bdv: u8,
+    pub n2ocp: bool,
+    pub ddx: u8,
+    pub interval: DataInterval,
+}
+
+/// Last values written to the configuration registers, `None` until a
+/// register is written after reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub psr: Option<PSRFlags>,
+    pub pwr: Option<PowerSetting>,
+    pub cdi: Option<DataIntervalSetting>,
+    /// source to gate and gate to source non-overlap
+    pub tcon: Option<(NonOverlap, NonOverlap)>,
+    /// width and height
+    pub tres: Option<(u16, u16)>,
+    pub pll: Option<FrameRate>,
+    pub vcom: Option<VcomLevel>,
+}
+
+pub mod opcode {
+    pub use crate::controller::uc_family::opcode::*;
+
+    pub const PSR: u8 = 0x00;
+    pub const PWR: u8 = 0x01;
+    pub const PFS: u8 = 0x03;
+    pub const BTST: u8 = 0x06;
+    pub const DTM2: u8 = 0x13;
+    pub const DUSPI: u8 = 0x15;
+    pub const LUTC: u8 = 0x20;
+    pub const LUTWW: u8 = 0x21;
+    pub const LUTKW: u8 = 0x22;
+    pub const LUTWK: u8 = 0x23;
+    pub const LUTKK: u8 = 0x24;
+    pub const LUTBD: u8 = 0x25;
+    pub const PLL: u8 = 0x30;
+    pub const CDI: u8 = 0x50;
+    pub const TCON: u8 = 0x60;
+    pub const GSST: u8 = 0x65;
+    pub const FLG: u8 = 0x71;
+    pub const PTL: u8 = 0x90;
+    pub const PTIN: u8 = 0x91;
+    pub const PTOUT: u8 = 0x92;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LutRegister {
+    Vcom,
+    WhiteToWhite,
+    BlackToWhite,
+    WhiteToBlack,
+    BlackToBlack,
+    Border,
+}
+
+impl LutRegister {
+    fn opcode(&self) -> u8 {
+        match self {
+            LutRegister::Vcom => opcode::LUTC,
+            LutRegister::WhiteToWhite => opcode::LUTWW,
+            LutRegister::BlackToWhite => opcode::LUTKW,
+            LutRegister::WhiteToBlack => opcode::LUTWK,
+            LutRegister::BlackToBlack => opcode::LUTKK,
+            LutRegister::Border => opcode::LUTBD,
+        }
+    }
+}
+
+/// A GD7965 command with its parameters. `Other` holds anything not
+/// modelled here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    PanelSetting(PSRFlags),
+    PowerSetting(PowerSetting),
+    PowerOff,
+    PowerOffSequence(PowerOffDelay),
+    PowerOn,
+    BoosterSoftStart(BoosterSoftStart),
+    DeepSleep,
+    DataStartTransmission1(&'a [u8]),
+    DisplayRefresh,
+    DataStartTransmission2(&'a [u8]),
+    DualSpi { mm_en: bool, duspi_en: bool },
+    Lut(LutRegister, &'a [u8]),
+    PllControl(FrameRate),
+    VcomAndDataInterval(DataIntervalSetting),
+    TconSetting { s2g: NonOverlap, g2s: NonOverlap },
+    Resolution { width: u16, height: u16 },
+    GateSourceStart { hst: u16, vst: u16 },
+    Revision,
+    GetStatus,
+    VcomDc(VcomLevel),
+    PartialWindow { hrst: u16, hred: u16, vrst: u16, vred: u16, pt_scan: bool },
+    PartialIn,
+    PartialOut,
+    Other(u8, &'a [u8]),
+}
+
+impl<'a> Command<'a> {
+    pub fn encode(&self) -> Encoded<'a> {
+        match *self {
+            Command::PanelSetting(flags) => Encoded::new(opcode::PSR, &[flags.bits]),
+            Command::PowerSetting(pwr) => {
+                let [flags_high, flags_low] = pwr.flags.bits.to_be_bytes();
+                Encoded::new(opcode::PWR, &[flags_high, flags_low, pwr.vdh.level(), pwr.vdl.level(), pwr.vdhr.level()])
+            }
+            Command::PowerOff => Encoded::new(opcode::POF, &[]),
+            Command::PowerOffSequence(t_vds_off) => Encoded::new(opcode::PFS, &[(t_vds_off as u8) << 4]),
+            Command::PowerOn => Encoded::new(opcode::PON, &[]),
+            Command::BoosterSoftStart(btst) => Encoded::new(opcode::BTST, &[btst.pha, btst.phb, btst.phc, (btst.phc2en as u8) << 7 | btst.phc2]),
+            Command::DeepSleep => Encoded::new(opcode::DSLP, &[opcode::DEEP_SLEEP_CHECK]),
+            Command::DataStartTransmission1(data) => Encoded::borrowed(opcode::DTM1, data),
+            Command::DisplayRefresh => Encoded::new(opcode::DRF, &[]),
+            Command::DataStartTransmission2(data) => Encoded::borrowed(opcode::DTM2, data),
+            Command::DualSpi { mm_en, duspi_en } => Encoded::new(opcode::DUSPI, &[(mm_en as u8) << 5 | (duspi_en as u8) << 4]),
+            Command::Lut(register, lut) => Encoded::borrowed(register.opcode(), lut),
+            Command::PllControl(frs) => Encoded::new(opcode::PLL, &[frs.code()]),
+            Command::VcomAndDataInterval(cdi) => Encoded::new(opcode::CDI, &[
+                (cdi.bdz as u8) << 7 | cdi.bdv << 4 | (cdi.n2ocp as u8) << 3 | cdi.ddx,
+                cdi.interval.code()]),
+            Command::TconSetting { s2g, g2s } => Encoded::new(opcode::TCON, &[s2g.code() << 4 | g2s.code()]),
+            // this doesn't seem to match the spec but it's what the demo code does
+            Command::Resolution { width, height } => Encoded::new(opcode::TRES, &[(width >> 8) as u8, width as u8, (height >> 8) as u8, height as u8]),
+            Command::GateSourceStart { hst, vst } => Encoded::new(opcode::GSST, &[(hst >> 8) as u8, hst as u8, (vst >> 8) as u8, vst as u8]),
+            Command::Revision => Encoded::new(opcode::REV, &[]),
+            Command::GetStatus => Encoded::new(opcode::FLG, &[]),
+            Command::VcomDc(vdcs) => Encoded::new(opcode::VDCS, &[vdcs.code()]),
+            Command::PartialWindow { hrst, hred, vrst, vred, pt_scan } => Encoded::new(opcode::PTL, &[
+                (hrst >> 8) as u8,
+                hrst as u8,
+                (hred >> 8) as u8,
+                hred as u8,
+                (vrst >> 8) as u8,
+                vrst as u8,
+                (vred >> 8) as u8,
+                vred as u8,
+                pt_scan as u8]),
+            Command::PartialIn => Encoded::new(opcode::PTIN, &[]),
+            Command::PartialOut => Encoded::new(opcode::PTOUT, &[]),
+            Command::Other(op, data) => Encoded::borrowed(op, data),
+        }
+    }
+
+    /// Parses a captured command, `data` holds all parameter bytes sent
+    /// after the opcode.
+    pub fn decode(op: u8, data: &'a [u8]) -> DecodeResult<Command<'a>> {
+        let command = match op {
+            opcode::PSR => {
+                let [bits] = expect_params(data)?;
+                Command::PanelSetting(PSRFlags::from_bits(bits).ok_or(ConfigError::ValueOutOfRange)?)
+            }
+            opcode::PWR => {
+                let [flags_high, flags_low, vdh, vdl, vdhr] = expect_params(data)?;
+                let flags = PWRFlags::from_bits(be_u16(flags_high, flags_low)).ok_or(ConfigError::ValueOutOfRange)?;
+                Command::PowerSetting(PowerSetting {
+                    flags,
+                    vdh: SourceVoltage::from_level(vdh)?,
+                    vdl: NegativeSourceVoltage::from_level(vdl)?,
+                    vdhr: SourceVoltage::from_level(vdhr)?,
+                })
+            }
+            opcode::POF => { expect_params::<0>(data)?; Command::PowerOff }
+            opcode::PFS => {
+                let [t_vds_off] = expect_params(data)?;
+                Command::PowerOffSequence(match t_vds_off {
+                    0x00 => PowerOffDelay::Frames1,
+                    0x10 => PowerOffDelay::Frames2,
+                    0x20 => PowerOffDelay::Frames3,
+                    0x30 => PowerOffDelay::Frames4,
+                    _ => return Err(ConfigError::ValueOutOfRange.into()),
+                })
+            }
+            opcode::PON => { expect_params::<0>(data)?; Command::PowerOn }
+            opcode::BTST => {
+                let [pha, phb, phc, phc2] = expect_params(data)?;
+                Command::BoosterSoftStart(BoosterSoftStart { pha, phb, phc, phc2en: phc2 & 0x80 != 0, phc2: phc2 & 0x7f })
+            }
+            opcode::DSLP => match expect_params(data)? {
+                [opcode::DEEP_SLEEP_CHECK] => Command::DeepSleep,
+                _ => return Err(ConfigError::ValueOutOfRange.into()),
+            },
+            opcode::DTM1 => Command::DataStartTransmission1(data),
+            opcode::DRF => { expect_params::<0>(data)?; Command::DisplayRefresh }
+            opcode::DTM2 => Command::DataStartTransmission2(data),
+            opcode::DUSPI => {
+                let [flags] = expect_params(data)?;
+                Command::DualSpi { mm_en: flags & 0x20 != 0, duspi_en: flags & 0x10 != 0 }
+            }
+            opcode::LUTC => Command::Lut(LutRegister::Vcom, data),
+            opcode::LUTWW => Command::Lut(LutRegister::WhiteToWhite, data),
+            opcode::LUTKW => Command::Lut(LutRegister::BlackToWhite, data),
+            opcode::LUTWK => Command::Lut(LutRegister::WhiteToBlack, data),
+            opcode::LUTKK => Command::Lut(LutRegister::BlackToBlack, data),
+            opcode::LUTBD => Command::Lut(LutRegister::Border, data),
+            opcode::PLL => {
+                let [frs] = expect_params(data)?;
+                Command::PllControl(FrameRate::from_code(frs)?)
+            }
+            opcode::CDI => {
+                let [flags, interval] = expect_params(data)?;
+                Command::VcomAndDataInterval(DataIntervalSetting {
+                    bdz: flags & 0x80 != 0,
+                    bdv: flags >> 4 & 0x3,
+                    n2ocp: flags & 0x08 != 0,
+                    ddx: flags & 0x3,
+                    interval: DataInterval::from_code(interval)?,
+                })
+            }
+            opcode::TCON => {
+                let [tcon] = expect_params(data)?;
+                Command::TconSetting { s2g: NonOverlap(tcon >> 4), g2s: NonOverlap(tcon & 0xf) }
+            }
+            opcode::TRES => {
+                let [width_high, width_low, height_high, height_low] = expect_params(data)?;
+                Command::Resolution { width: be_u16(width_high, width_low), height: be_u16(height_high, height_low) }
+            }
+            opcode::GSST => {
+                let [hst_high, hst_low, vst_high, vst_low] = expect_params(data)?;
+                Command::GateSourceStart { hst: be_u16(hst_high, hst_low), vst: be_u16(vst_high, vst_low) }
+            }
+            opcode::REV => { expect_params::<0>(data)?; Command::Revision }
+            opcode::FLG => { expect_params::<0>(data)?; Command::GetStatus }
+            opcode::VDCS => {
+                let [vdcs] = expect_params(data)?;
+                Command::VcomDc(VcomLevel::from_code(vdcs)?)
+            }
+            opcode::PTL => {
+                let [hrst_high, hrst_low, hred_high, hred_low, vrst_high, vrst_low, vred_high, vred_low, pt_scan] = expect_params(data)?;
+                Command::PartialWindow {
+                    hrst: be_u16(hrst_high, hrst_low),
+                    hred: be_u16(hred_high, hred_low),
+                    vrst: be_u16(vrst_high, vrst_low),
+                    vred: be_u16(vred_high, vred_low),
+                    pt_scan: pt_scan & 1 != 0,
+                }
+            }
+            opcode::PTIN => { expect_params::<0>(data)?; Command::PartialIn }
+            opcode::PTOUT => { expect_params::<0>(data)?; Command::PartialOut }
+            _ => Command::Other(op, data),
+        };
+        Ok(command)
+    }
+}
+
+pub struct GD7965<T> where T: DisplayConnector {
+    connector: T,
+    registers: Registers,
 }
 
 impl<T> GD7965<T> where T: DisplayConnector {
     pub fn new(connector: T) -> GD7965<T> {
         GD7965 {
-            connector
+            connector,
+            registers: Registers::default(),
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn reset(&mut self) -> Result<()> {
-        self.connector.reset()
+    /// The configuration as far as it was written since the last reset.
+    pub fn registers(&self) -> &Registers {
+        &self.registers
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn psr_panel_setting(&mut self, psr_flags: PSRFlags) -> Result<()> {
-        self.connector.send_command(0)?;
-        self.connector.send_data(&psr_flags.bits.to_be_bytes())
+    fn send(&mut self, command: Command) -> Result<()> {
+        command.encode().send(&mut self.connector)
     }
 
     #[allow(dead_code)]
-    pub(crate) fn pwr_power_setting(&mut self, pwr_flags: PWRFlags, vdh_lvl_volts:f32, vdl_lvl_volts:f32, vdhr_lvl_volts:f32 ) -> Result<()> { //, vdps_lv: u8, vdns_lv: u8) {
-
-        assert!(vdh_lvl_volts>=2.4&& vdh_lvl_volts<=15.0);
-        assert!(vdl_lvl_volts>=-15.0&& vdl_lvl_volts<=-2.4);
-        assert!(vdhr_lvl_volts>=2.4&& vdhr_lvl_volts<=15.0);
-
-        let vdh_lvl = 0x3f&(0.5 + (vdh_lvl_volts-2.4) / 0.2) as u8;
-        let vdl_lvl = 0x3f&(0.5 + (-vdl_lvl_volts-2.4) / 0.2) as u8;
-        let vdhr_lvl = 0x3f&(0.5 + (vdhr_lvl_volts-2.4) / 0.2) as u8;
+    pub fn psr_panel_setting(&mut self, psr_flags: PSRFlags) -> Result<()> {
+        self.send(Command::PanelSetting(psr_flags))?;
+        self.registers.psr = Some(psr_flags);
+        Ok(())
+    }
 
-        self.connector.send_command(1)?;
-        self.connector.send_data(&pwr_flags.bits.to_be_bytes())?;
-        self.connector.send_data(&[ vdh_lvl, vdl_lvl, vdhr_lvl])
+    /// Like `psr_panel_setting` but only sent if the value changed.
+    pub fn update_psr(&mut self, psr_flags: PSRFlags) -> Result<()> {
+        if self.registers.psr == Some(psr_flags) {
+            return Ok(());
+        }
+        self.psr_panel_setting(psr_flags)
     }
 
     #[allow(dead_code)]
-    pub fn pof_power_off(&mut self) -> Result<()> {
-        self.connector.send_command(2)
+    pub fn pwr_power_setting(&mut self, pwr_flags: PWRFlags, vdh: SourceVoltage, vdl: NegativeSourceVoltage, vdhr: SourceVoltage) -> Result<()> { //, vdps_lv: u8, vdns_lv: u8) {
+        let setting = PowerSetting { flags: pwr_flags, vdh, vdl, vdhr };
+        self.send(Command::PowerSetting(setting))?;
+        self.registers.pwr = Some(setting);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn pfs_power_off_sequence_setting(&mut self, t_vds_off: u8) -> Result<()> {
-        self.connector.send_command(3)?;
-        self.connector.send_data(&[(t_vds_off & 3) <<4 ])
+    pub fn update_pwr(&mut self, setting: PowerSetting) -> Result<()> {
+        if self.registers.pwr == Some(setting) {
+            return Ok(());
+        }
+        self.pwr_power_setting(setting.flags, setting.vdh, setting.vdl, setting.vdhr)
     }
 
     #[allow(dead_code)]
-    pub fn pon_power_on(&mut self) -> Result<()> {
-        self.connector.send_command(4)
+    pub fn pfs_power_off_sequence_setting(&mut self, t_vds_off: PowerOffDelay) -> Result<()> {
+        self.send(Command::PowerOffSequence(t_vds_off))
     }
 
     #[allow(dead_code)]
     pub fn btst_booster_soft_start(&mut self, pha: u8, phb: u8, phc: u8, phc2en: bool, phc2 : u8) -> Result<()> {
-        self.connector.send_command(6)?;
-        self.connector.send_data(&[pha, phb, phc, (phc2en as u8) << 7 | phc2])
+        self.send(Command::BoosterSoftStart(BoosterSoftStart::new(pha, phb, phc, phc2en, phc2)?))
     }
 
     #[allow(dead_code)]
-    pub fn dslp_deep_sleep(&mut self) -> Result<()> {
-        self.connector.send_command(7)?;
-        self.connector.send_data(&[0xa5])
+    pub fn duspi_dual_spi_mode(&mut self, mm_en:bool, duspi_en:bool) -> Result<()> {
+        self.send(Command::DualSpi { mm_en, duspi_en })
     }
 
     #[allow(dead_code)]
-    pub fn duspi_dual_spi_mode(&mut self, mm_en:bool, duspi_en:bool) -> Result<()> {
-        self.connector.send_command(0x15)?;
-        self.connector.send_data(&[(mm_en as u8) << 5 | (duspi_en as u8) << 4])
+    pub fn pll_control(&mut self, frs: FrameRate) -> Result<()> {
+        self.send(Command::PllControl(frs))?;
+        self.registers.pll = Some(frs);
+        Ok(())
+    }
+
+    pub fn update_pll(&mut self, frs: FrameRate) -> Result<()> {
+        if self.registers.pll == Some(frs) {
+            return Ok(());
+        }
+        self.pll_control(frs)
     }
 
     #[allow(dead_code)]
-    pub fn drf_display_refresh(&mut self) -> Result<()> {
-        self.connector.send_command(0x12)
+    pub fn cdi_vcom_and_data_interval_settings(&mut self, bdz: bool, bdv: u8, n2ocp: bool, ddx: u8, cdi: DataInterval) -> Result<()> {
+        check_range(bdv, 3, ConfigError::ValueOutOfRange)?;
+        check_range(ddx, 3, ConfigError::ValueOutOfRange)?;
+        let setting = DataIntervalSetting { bdz, bdv, n2ocp, ddx, interval: cdi };
+        self.send(Command::VcomAndDataInterval(setting))?;
+        self.registers.cdi = Some(setting);
+        Ok(())
+    }
+
+    pub fn update_cdi(&mut self, setting: DataIntervalSetting) -> Result<()> {
+        if self.registers.cdi == Some(setting) {
+            return Ok(());
+        }
+        self.cdi_vcom_and_data_interval_settings(setting.bdz, setting.bdv, setting.n2ocp, setting.ddx, setting.interval)
+    }
+
+    /// Changes only the border bits of CDI, which has to have been written
+    /// since the last reset.
+    pub fn update_border(&mut self, bdz: bool, bdv: u8) -> Result<()> {
+        let cdi = self.registers.cdi.ok_or(ConfigError::NotConfigured)?;
+        self.update_cdi(DataIntervalSetting { bdz, bdv, ..cdi })
     }
 
     #[allow(dead_code)]
-    pub fn pll_control(&mut self, frs: u8) -> Result<()> {
-        self.connector.send_command(0x30)?;
-        self.connector.send_data(&[frs])
+    pub fn tcon_setting(&mut self, s2g: NonOverlap, g2s: NonOverlap) -> Result<()> {
+        self.send(Command::TconSetting { s2g, g2s })?;
+        self.registers.tcon = Some((s2g, g2s));
+        Ok(())
+    }
+
+    pub fn update_tcon(&mut self, s2g: NonOverlap, g2s: NonOverlap) -> Result<()> {
+        if self.registers.tcon == Some((s2g, g2s)) {
+            return Ok(());
+        }
+        self.tcon_setting(s2g, g2s)
+    }
+
+    pub fn update_tres(&mut self, width: u16, height: u16) -> Result<()> {
+        if self.registers.tres == Some((width, height)) {
+            return Ok(());
+        }
+        self.tres_resolution(width, height)
+    }
+
+    pub fn update_vcom(&mut self, vdcs: VcomLevel) -> Result<()> {
+        if self.registers.vcom == Some(vdcs) {
+            return Ok(());
+        }
+        self.vcom_dc_setting(vdcs)
     }
 
     #[allow(dead_code)]
-    pub fn cdi_vcom_and_data_interval_settings(&mut self, bdz: bool, bdv: u8, n2ocp: bool, ddx: u8, cdi: u8) -> Result<()> {
-        self.connector.send_command(0x50)?;
-        self.connector.send_data(&[(bdz as u8) << 7 | (bdv & 3) << 4 | (n2ocp as u8) << 3 | ddx & 3, cdi & 7])
+    pub fn gss_gate_source_start_setting(&mut self, hst: u16, vst: u16) -> Result<()> {
+        // HST only has bits 9 to 3, so it has to be a multiple of 8
+        if hst > 0x3f8 || hst % 8 != 0 || vst > 0x3ff {
+            return Err(ConfigError::OffsetOutOfRange.into());
+        }
+        self.send(Command::GateSourceStart { hst, vst })
     }
 
     #[allow(dead_code)]
-    pub fn tcon_setting(&mut self, s2g: u8, g2s: u8) -> Result<()> {
-        self.connector.send_command(0x60)?;
-        self.connector.send_data(&[(s2g & 7) << 4 | g2s & 7])
+    pub fn ptl_partial_window(&mut self, hrst: u16, hred: u16, vrst: u16, vred: u16, pt_scan: bool) -> Result<()> {
+        // horizontal start and end are in whole bytes, start on bit 0 and end on bit 7
+        if hrst % 8 != 0 || hred % 8 != 7 || hrst > hred || hred > 0x3ff || vrst > vred || vred > 0x3ff {
+            return Err(ConfigError::OffsetOutOfRange.into());
+        }
+        self.send(Command::PartialWindow { hrst, hred, vrst, vred, pt_scan })
     }
 
     #[allow(dead_code)]
-    pub fn tres_resolution(&mut self, width: u16, height: u16) -> Result<()> {
-        self.connector.send_command(0x61)?;
-        // this doesn't seem to match the spec but it's what the demo code does
-        self.connector.send_data(&[(width >> 8) as u8, (width & 0xff) as u8, (height >> 8) as u8, (height & 0xff) as u8])
+    pub fn ptin_partial_in(&mut self) -> Result<()> {
+        self.send(Command::PartialIn)
     }
 
     #[allow(dead_code)]
-    pub fn gss_gate_source_start_setting(&mut self, hst: u16, vst: u16) -> Result<()> {
-        self.connector.send_command(0x65)?;
-        self.connector.send_data(&[
-            0x3 & (hst >> 8) as u8,
-            0xf1 & hst as u8,
-            0x3 & (vst >> 8) as u8,
-            vst as u8])
+    pub fn ptout_partial_out(&mut self) -> Result<()> {
+        self.send(Command::PartialOut)
     }
 
+    // LUTs are only used with PSRFlags::REG set, the length depends on
+    // the waveform so it is left to the caller
     #[allow(dead_code)]
-    pub fn vcom_dc_setting(&mut self, vdcs: u8) -> Result<()> {
-        self.connector.send_command(0x82)?;
-        self.connector.send_data(&[vdcs])
+    pub fn lutc_vcom_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::Vcom, lut))
     }
 
     #[allow(dead_code)]
-    pub fn transmit(&mut self, data: &[u8]) -> Result<()> {
-        self.connector.send_command(0x10)?;
-        self.connector.send_data(data)
+    pub fn lutww_white_to_white_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::WhiteToWhite, lut))
     }
 
     #[allow(dead_code)]
-    pub(crate) fn transmit_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
-        self.connector.send_command(0x10)?;
-        self.connector.send_data_with(repeats, &source)
+    pub fn lutkw_black_to_white_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::BlackToWhite, lut))
+    }
 
+    #[allow(dead_code)]
+    pub fn lutwk_white_to_black_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::WhiteToBlack, lut))
     }
 
     #[allow(dead_code)]
-    pub(crate) fn transmit_with2<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
-        self.connector.send_command(0x13)?;
-        self.connector.send_data_with(repeats, source)
+    pub fn lutkk_black_to_black_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::BlackToBlack, lut))
+    }
 
+    #[allow(dead_code)]
+    pub fn lutbd_border_lut(&mut self, lut: &[u8]) -> Result<()> {
+        self.send(Command::Lut(LutRegister::Border, lut))
     }
 
     #[allow(dead_code)]
-    pub fn flash_data(&mut self) -> Result<()> {
-        self.connector.send_command(0xb9)
+    pub fn transmit2(&mut self, data: &[u8]) -> Result<()> {
+        self.send(Command::DataStartTransmission2(data))
     }
 
     #[allow(dead_code)]
-    pub fn await_ready_state(&mut self) -> Result<()> {
-        self.connector.delay_ms(100);
-        self.connector.send_command(0x71)?;
-        while self.connector.is_busy()? {
-            self.connector.send_command(0x71)?;
+    pub fn transmit_with2<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        let dtm2 = Command::DataStartTransmission2(&[]).encode();
+        self.connector.send_command(dtm2.opcode())?;
+        self.connector.send_data_with(repeats, source)
+    }
+}
+
+impl<'a> From<SharedCommand<'a>> for Command<'a> {
+    fn from(command: SharedCommand<'a>) -> Self {
+        match command {
+            SharedCommand::PowerOff => Command::PowerOff,
+            SharedCommand::PowerOn => Command::PowerOn,
+            SharedCommand::DeepSleep => Command::DeepSleep,
+            SharedCommand::DataStartTransmission1(data) => Command::DataStartTransmission1(data),
+            SharedCommand::DisplayRefresh => Command::DisplayRefresh,
+            SharedCommand::FlashData => Command::Other(opcode::FLASH, &[]),
+            SharedCommand::Revision => Command::Revision,
         }
-        self.connector.delay_ms(200)?;
-        /*
-                self.connector.send_command(0x71)?;
-        while self.connector.is_busy()? { self.connector.delay_ms(1)?; }
+    }
+}
+
+impl<T> UcFamilyController for GD7965<T> where T: DisplayConnector {
+    type Connector = T;
+    type VcomLevel = VcomLevel;
+
+    // reset pulse from the vendor sample code, every poll sends a status command
+    const RESET_LOW_MS: u16 = 2;
+    const RESET_SETTLE_MS: u16 = 10;
+    const BUSY_POLL_MS: u16 = 5;
+
+    fn connector(&mut self) -> &mut T {
+        &mut self.connector
+    }
+
+    fn encode_shared(command: SharedCommand) -> Encoded {
+        Command::from(command).encode()
+    }
+
+    fn vcom_dc_setting(&mut self, vdcs: VcomLevel) -> Result<()> {
+        self.send(Command::VcomDc(vdcs))?;
+        self.registers.vcom = Some(vdcs);
         Ok(())
-         */
+    }
+
+    fn tres_resolution(&mut self, width: u16, height: u16) -> Result<()> {
+        self.send(Command::Resolution { width, height })?;
+        self.registers.tres = Some((width, height));
         Ok(())
     }
+
+    fn forget_registers(&mut self) {
+        self.registers = Registers::default();
+    }
+
+    // busy is only updated after a get status command
+    fn is_busy(&mut self) -> Result<bool> {
+        self.send(Command::GetStatus)?;
+        self.connector.is_busy()
+    }
+
 }
 
 #[cfg(test)]
 mod tests {
     use std::vec::Vec;
     use crate::controller::display_connector::{DisplayConnector,Result};
-    use crate::controller::gd7965::{GD7965, PWRFlags};
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::controller::display_connector::Error;
+    use crate::controller::command::DecodeError;
+    use crate::controller::gd7965::{BoosterSoftStart, Command, DataIntervalSetting, FrameRate, GD7965, LutRegister, PowerOffDelay, PowerSetting, PSRFlags, PWRFlags, SourceVoltage, NegativeSourceVoltage, VcomLevel};
+    use crate::controller::settings::{ConfigError, DataInterval, NonOverlap};
     use core::cell::RefCell;
 
 
@@ -209,7 +684,7 @@ mod tests {
     }
 
     impl DisplayConnector for DataRecorder<'_> {
-        fn reset(&mut self) -> Result<()> {
+        fn reset(&mut self, _low_ms: u16) -> Result<()> {
             unimplemented!()
         }
 
@@ -243,7 +718,7 @@ mod tests {
         let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
 
         driver.pwr_power_setting(PWRFlags::VSR_EN|PWRFlags::VS_EN|PWRFlags::VG_EN|PWRFlags::VG_LVL_20V,
-        15.0,-15.0, 3.0);
+        SourceVoltage::from_volts(15.0).unwrap(), NegativeSourceVoltage::from_volts(-15.0).unwrap(), SourceVoltage::from_volts(3.0).unwrap()).unwrap();
 
         assert_eq!( cmd_bytes.len(), 1 as usize);
         assert_eq!( data_bytes.len(), 5 as usize);
@@ -305,7 +780,7 @@ mod tests {
         let mut data_bytes = Vec::new();
         let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
 
-        driver.cdi_vcom_and_data_interval_settings(false, 1, false, 1, 7);
+        driver.cdi_vcom_and_data_interval_settings(false, 1, false, 1, DataInterval::from_code(7).unwrap()).unwrap();
 
         assert_eq!( cmd_bytes.len(), 1 as usize);
         assert_eq!( data_bytes.len(), 2 as usize );
@@ -320,7 +795,7 @@ mod tests {
         let mut data_bytes = Vec::new();
         let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
 
-        driver.tcon_setting(2,2);
+        driver.tcon_setting(NonOverlap::from_code(2).unwrap(), NonOverlap::from_code(2).unwrap()).unwrap();
 
         assert_eq!( cmd_bytes.len(), 1 as usize);
         assert_eq!( data_bytes.len(), 1 as usize );
@@ -328,6 +803,107 @@ mod tests {
         assert_eq!( data_bytes[0], 0x22);
     }
 
+    #[test]
+    fn test_voltage_ranges() {
+        assert_eq!(SourceVoltage::from_volts(2.4).unwrap().level(), 0);
+        assert_eq!(SourceVoltage::from_volts(15.2), Err(ConfigError::VoltageOutOfRange));
+        assert_eq!(NegativeSourceVoltage::from_volts(-2.0), Err(ConfigError::VoltageOutOfRange));
+        assert_eq!(SourceVoltage::from_volts(f32::NAN), Err(ConfigError::VoltageOutOfRange));
+    }
 
-}
+    #[test]
+    fn test_gss_gate_source_start_setting() {
+        let mut cmd_bytes = Vec::new();
+        let mut data_bytes = Vec::new();
+        let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
+
+        driver.gss_gate_source_start_setting(0x108, 0x101).unwrap();
+        assert!(matches!(driver.gss_gate_source_start_setting(0x109, 0), Err(Error::ConfigError(ConfigError::OffsetOutOfRange))));
+        assert!(matches!(driver.cdi_vcom_and_data_interval_settings(false, 4, false, 1, DataInterval::from_code(7).unwrap()), Err(Error::ConfigError(ConfigError::ValueOutOfRange))));
+
+        assert_eq!(cmd_bytes, [0x65]);
+        assert_eq!(data_bytes, [0x01, 0x08, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_ptl_partial_window() {
+        let mut cmd_bytes = Vec::new();
+        let mut data_bytes = Vec::new();
+        let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
 
+        driver.ptl_partial_window(8, 0x31f, 2, 0x1df, true).unwrap();
+        assert!(matches!(driver.ptl_partial_window(4, 15, 0, 0, true), Err(Error::ConfigError(ConfigError::OffsetOutOfRange))));
+
+        assert_eq!(cmd_bytes, [0x90]);
+        assert_eq!(data_bytes, [0x00, 0x08, 0x03, 0x1f, 0x00, 0x02, 0x01, 0xdf, 0x01]);
+    }
+
+    #[test]
+    fn test_register_shadow() {
+        let mut cmd_bytes = Vec::new();
+        let mut data_bytes = Vec::new();
+        let mut driver = GD7965::new(DataRecorder { cmds : & mut cmd_bytes, data: & mut data_bytes });
+
+        // the border can't be changed before CDI is known
+        assert!(matches!(driver.update_border(true, 0), Err(Error::ConfigError(ConfigError::NotConfigured))));
+        driver.cdi_vcom_and_data_interval_settings(false, 1, false, 1, DataInterval::from_code(7).unwrap()).unwrap();
+        driver.update_border(false, 2).unwrap();
+        driver.update_border(false, 2).unwrap();
+        driver.update_psr(PSRFlags::UD | PSRFlags::SHL).unwrap();
+        driver.update_psr(PSRFlags::UD | PSRFlags::SHL).unwrap();
+        driver.update_tres(800, 480).unwrap();
+        driver.update_tres(800, 480).unwrap();
+        assert_eq!(driver.registers().cdi.unwrap().bdv, 2);
+        assert_eq!(driver.registers().tres, Some((800, 480)));
+
+        driver.forget_registers();
+        assert!(driver.registers().psr.is_none());
+        driver.update_psr(PSRFlags::UD | PSRFlags::SHL).unwrap();
+
+        assert_eq!(cmd_bytes, [0x50, 0x50, 0x00, 0x61, 0x00]);
+        assert_eq!(data_bytes, [0x11, 0x07, 0x21, 0x07, 0x0c, 0x03, 0x20, 0x01, 0xe0, 0x0c]);
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let lut = [0x00, 0x0f, 0x0f];
+        let commands = [
+            Command::PanelSetting(PSRFlags::KW_R | PSRFlags::SHL | PSRFlags::SHD_N | PSRFlags::RST_N),
+            Command::PowerSetting(PowerSetting {
+                flags: PWRFlags::VS_EN | PWRFlags::VG_EN | PWRFlags::VG_LVL_20V,
+                vdh: SourceVoltage::from_level(0x3f).unwrap(),
+                vdl: NegativeSourceVoltage::from_level(0x3f).unwrap(),
+                vdhr: SourceVoltage::from_level(0x03).unwrap(),
+            }),
+            Command::PowerOffSequence(PowerOffDelay::Frames3),
+            Command::BoosterSoftStart(BoosterSoftStart::new(0x17, 0x17, 0x28, true, 0x17).unwrap()),
+            Command::DeepSleep,
+            Command::DualSpi { mm_en: false, duspi_en: true },
+            Command::Lut(LutRegister::WhiteToBlack, &lut),
+            Command::PllControl(FrameRate::from_code(6).unwrap()),
+            Command::VcomAndDataInterval(DataIntervalSetting { bdz: false, bdv: 1, n2ocp: true, ddx: 1, interval: DataInterval::from_code(7).unwrap() }),
+            Command::TconSetting { s2g: NonOverlap::from_code(2).unwrap(), g2s: NonOverlap::from_code(2).unwrap() },
+            Command::GateSourceStart { hst: 8, vst: 0 },
+            Command::VcomDc(VcomLevel::from_code(0x26).unwrap()),
+            Command::PartialWindow { hrst: 8, hred: 0x27f, vrst: 0, vred: 0x1df, pt_scan: true },
+            Command::PartialOut,
+            Command::Other(0x40, &[0x01]),
+        ];
+        for command in commands.iter() {
+            let encoded = command.encode();
+            assert_eq!(Command::decode(encoded.opcode(), encoded.params()), Ok(*command));
+        }
+        assert_eq!(Command::PowerOffSequence(PowerOffDelay::Frames3).encode().params(), [0x20]);
+        // PHC2 would spill into the enable bit
+        assert_eq!(BoosterSoftStart::new(0x17, 0x17, 0x28, false, 0x80), Err(ConfigError::ValueOutOfRange));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Command::decode(0x90, &[0; 8]), Err(DecodeError::Length));
+        assert_eq!(Command::decode(0x12, &[0x00]), Err(DecodeError::Length));
+        assert_eq!(Command::decode(0x03, &[0x01]), Err(DecodeError::Value(ConfigError::ValueOutOfRange)));
+        assert_eq!(Command::decode(0x30, &[0x10]), Err(DecodeError::Value(ConfigError::FrameRateOutOfRange)));
+        assert_eq!(Command::decode(0x01, &[0x00, 0x07, 0x40, 0x00, 0x00]), Err(DecodeError::Value(ConfigError::VoltageOutOfRange)));
+    }
+}
@@ -1,4 +1,7 @@
+use crate::controller::command::{be_u16, expect_params, DecodeResult, Encoded};
 use crate::controller::display_connector::{DisplayConnector,Result};
+use crate::controller::uc_family::{SharedCommand, UcFamilyController};
+use crate::controller::settings::{check_range, ConfigError, ConfigResult, DataInterval, NonOverlap};
 
 
 /*
@@ -49,149 +52,452 @@ bitflags! {
     }
 }
 
+/// PLL frame rate code, M in bits 5-3 and N in bits 2-0, both 1 to 7.
+/// 0x3c (M=7, N=4) is 50Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate(pub(crate) u8);
+
+impl FrameRate {
+    pub fn from_code(code: u8) -> ConfigResult<FrameRate> {
+        check_range(code, 0x3f, ConfigError::FrameRateOutOfRange)?;
+        if code & 0x38 == 0 || code & 0x07 == 0 {
+            return Err(ConfigError::FrameRateOutOfRange);
+        }
+        Ok(FrameRate(code))
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+/// VCOM DC level code, from 0 (-0.1V) to 0x3f in 0.05V steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcomLevel(pub(crate) u8);
+
+impl VcomLevel {
+    pub fn from_code(code: u8) -> ConfigResult<VcomLevel> {
+        check_range(code, 0x3f, ConfigError::VoltageOutOfRange).map(VcomLevel)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+/// VCOM and data interval setting register (CDI).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataIntervalSetting {
+    pub vbd: u8,
+    pub ddx: bool,
+    pub interval: DataInterval,
+}
+
+/// Last values written to the configuration registers, `None` until a
+/// register is written after reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub psr: Option<PSRFlags>,
+    pub pwr: Option<PWRFlags>,
+    pub cdi: Option<DataIntervalSetting>,
+    /// source to gate and gate to source non-overlap
+    pub tcon: Option<(NonOverlap, NonOverlap)>,
+    /// width and height
+    pub tres: Option<(u16, u16)>,
+    pub pll: Option<FrameRate>,
+    pub vcom: Option<VcomLevel>,
+}
+
+pub mod opcode {
+    pub use crate::controller::uc_family::opcode::*;
+
+    pub const PSR: u8 = 0x00;
+    pub const PWR: u8 = 0x01;
+    pub const PFS: u8 = 0x03;
+    pub const BTST: u8 = 0x06;
+    pub const PLL: u8 = 0x30;
+    pub const IPC: u8 = 0x31;
+    pub const TSE: u8 = 0x41;
+    pub const CDI: u8 = 0x50;
+    pub const TCON: u8 = 0x60;
+    pub const DAM: u8 = 0x65;
+    pub const DEFINE_FLASH: u8 = 0xe5;
+}
+
+/// An IL0371 command with its parameters. `Other` holds anything not
+/// modelled here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    PanelSetting(PSRFlags),
+    PowerSetting(PWRFlags),
+    PowerOff,
+    PowerOffSequence(PFSFlags),
+    PowerOn,
+    BoosterSoftStart { pha: u8, phb: u8, phc: u8 },
+    DeepSleep,
+    DataStartTransmission(&'a [u8]),
+    DisplayRefresh,
+    PllControl(FrameRate),
+    ImageProcess { enabled: bool, line_width: u8 },
+    TemperatureCalibration { tse: bool, to: u8 },
+    VcomAndDataInterval(DataIntervalSetting),
+    TconSetting { s2g: NonOverlap, g2s: NonOverlap },
+    Resolution { width: u16, height: u16 },
+    SpiFlashControl(bool),
+    Revision,
+    VcomDc(VcomLevel),
+    FlashData,
+    DefineFlash(u8),
+    Other(u8, &'a [u8]),
+}
+
+impl<'a> Command<'a> {
+    pub fn encode(&self) -> Encoded<'a> {
+        match *self {
+            Command::PanelSetting(flags) => Encoded::new(opcode::PSR, &flags.bits.to_be_bytes()),
+            Command::PowerSetting(flags) => Encoded::new(opcode::PWR, &flags.bits.to_be_bytes()),
+            Command::PowerOff => Encoded::new(opcode::POF, &[]),
+            Command::PowerOffSequence(flags) => Encoded::new(opcode::PFS, &[flags.bits]),
+            Command::PowerOn => Encoded::new(opcode::PON, &[]),
+            Command::BoosterSoftStart { pha, phb, phc } => Encoded::new(opcode::BTST, &[pha, phb, phc]),
+            Command::DeepSleep => Encoded::new(opcode::DSLP, &[opcode::DEEP_SLEEP_CHECK]),
+            Command::DataStartTransmission(data) => Encoded::borrowed(opcode::DTM1, data),
+            Command::DisplayRefresh => Encoded::new(opcode::DRF, &[]),
+            Command::PllControl(frame_rate) => Encoded::new(opcode::PLL, &[frame_rate.code()]),
+            Command::ImageProcess { enabled, line_width } => Encoded::new(opcode::IPC, &[(enabled as u8) << 4 | line_width]),
+            Command::TemperatureCalibration { tse, to } => Encoded::new(opcode::TSE, &[(tse as u8) << 7 | to]),
+            Command::VcomAndDataInterval(cdi) => Encoded::new(opcode::CDI, &[cdi.vbd << 5 | (cdi.ddx as u8) << 4 | cdi.interval.code()]),
+            Command::TconSetting { s2g, g2s } => Encoded::new(opcode::TCON, &[s2g.code() << 4 | g2s.code()]),
+            // this doesn't seem to match the spec but it's what the demo code does
+            Command::Resolution { width, height } => Encoded::new(opcode::TRES, &[(width >> 8) as u8, width as u8, (height >> 8) as u8, height as u8]),
+            Command::SpiFlashControl(dam) => Encoded::new(opcode::DAM, &[dam as u8]),
+            Command::Revision => Encoded::new(opcode::REV, &[]),
+            Command::VcomDc(vv) => Encoded::new(opcode::VDCS, &[vv.code()]),
+            Command::FlashData => Encoded::new(opcode::FLASH, &[]),
+            Command::DefineFlash(dunno) => Encoded::new(opcode::DEFINE_FLASH, &[dunno]),
+            Command::Other(op, data) => Encoded::borrowed(op, data),
+        }
+    }
+
+    /// Parses a captured command, `data` holds all parameter bytes sent
+    /// after the opcode.
+    pub fn decode(op: u8, data: &'a [u8]) -> DecodeResult<Command<'a>> {
+        let command = match op {
+            opcode::PSR => {
+                let [high, low] = expect_params(data)?;
+                Command::PanelSetting(PSRFlags::from_bits(be_u16(high, low)).ok_or(ConfigError::ValueOutOfRange)?)
+            }
+            opcode::PWR => {
+                let [high, low] = expect_params(data)?;
+                Command::PowerSetting(PWRFlags::from_bits(be_u16(high, low)).ok_or(ConfigError::ValueOutOfRange)?)
+            }
+            opcode::POF => { expect_params::<0>(data)?; Command::PowerOff }
+            opcode::PFS => {
+                let [bits] = expect_params(data)?;
+                Command::PowerOffSequence(PFSFlags::from_bits(bits).ok_or(ConfigError::ValueOutOfRange)?)
+            }
+            opcode::PON => { expect_params::<0>(data)?; Command::PowerOn }
+            opcode::BTST => {
+                let [pha, phb, phc] = expect_params(data)?;
+                Command::BoosterSoftStart { pha, phb, phc }
+            }
+            opcode::DSLP => match expect_params(data)? {
+                [opcode::DEEP_SLEEP_CHECK] => Command::DeepSleep,
+                _ => return Err(ConfigError::ValueOutOfRange.into()),
+            },
+            opcode::DTM1 => Command::DataStartTransmission(data),
+            opcode::DRF => { expect_params::<0>(data)?; Command::DisplayRefresh }
+            opcode::PLL => {
+                let [frame_rate] = expect_params(data)?;
+                Command::PllControl(FrameRate::from_code(frame_rate)?)
+            }
+            opcode::IPC => {
+                let [ipc] = expect_params(data)?;
+                check_range(ipc, 0x1f, ConfigError::ValueOutOfRange)?;
+                check_range(ipc & 0xf, 3, ConfigError::ValueOutOfRange)?;
+                Command::ImageProcess { enabled: ipc & 0x10 != 0, line_width: ipc & 0x3 }
+            }
+            opcode::TSE => {
+                let [tse] = expect_params(data)?;
+                check_range(tse & 0x7f, 0xf, ConfigError::ValueOutOfRange)?;
+                Command::TemperatureCalibration { tse: tse & 0x80 != 0, to: tse & 0xf }
+            }
+            opcode::CDI => {
+                let [cdi] = expect_params(data)?;
+                Command::VcomAndDataInterval(DataIntervalSetting {
+                    vbd: cdi >> 5,
+                    ddx: cdi & 0x10 != 0,
+                    interval: DataInterval::from_code(cdi & 0xf)?,
+                })
+            }
+            opcode::TCON => {
+                let [tcon] = expect_params(data)?;
+                Command::TconSetting { s2g: NonOverlap(tcon >> 4), g2s: NonOverlap(tcon & 0xf) }
+            }
+            opcode::TRES => {
+                let [width_high, width_low, height_high, height_low] = expect_params(data)?;
+                Command::Resolution { width: be_u16(width_high, width_low), height: be_u16(height_high, height_low) }
+            }
+            opcode::DAM => match expect_params(data)? {
+                [dam @ (0 | 1)] => Command::SpiFlashControl(dam == 1),
+                _ => return Err(ConfigError::ValueOutOfRange.into()),
+            },
+            opcode::REV => { expect_params::<0>(data)?; Command::Revision }
+            opcode::VDCS => {
+                let [vv] = expect_params(data)?;
+                Command::VcomDc(VcomLevel::from_code(vv)?)
+            }
+            opcode::FLASH => { expect_params::<0>(data)?; Command::FlashData }
+            opcode::DEFINE_FLASH => {
+                let [dunno] = expect_params(data)?;
+                Command::DefineFlash(dunno)
+            }
+            _ => Command::Other(op, data),
+        };
+        Ok(command)
+    }
+}
 
 pub struct IL0371<T> where T: DisplayConnector {
-    connector: T
+    connector: T,
+    registers: Registers,
 }
 
 
 impl<T> IL0371<T> where T: DisplayConnector {
     pub fn new(connector: T) -> IL0371<T> {
         IL0371 {
-            connector
+            connector,
+            registers: Registers::default(),
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn reset(&mut self) -> Result<()> {
-        self.connector.reset()
+    /// The configuration as far as it was written since the last reset.
+    pub fn registers(&self) -> &Registers {
+        &self.registers
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn psr_panel_setting(&mut self, psr_flags: PSRFlags) -> Result<()> {
-        self.connector.send_command(0)?;
-        self.connector.send_data(&psr_flags.bits.to_be_bytes())
+    fn send(&mut self, command: Command) -> Result<()> {
+        command.encode().send(&mut self.connector)
     }
 
     #[allow(dead_code)]
-    pub(crate) fn pwr_power_setting(&mut self, pwr_flags: PWRFlags) -> Result<()> { //, vdps_lv: u8, vdns_lv: u8) {
-        self.connector.send_command(1)?;
-        self.connector.send_data(&pwr_flags.bits.to_be_bytes())
+    pub fn psr_panel_setting(&mut self, psr_flags: PSRFlags) -> Result<()> {
+        self.send(Command::PanelSetting(psr_flags))?;
+        self.registers.psr = Some(psr_flags);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn pof_power_off(&mut self) -> Result<()>{
-        self.connector.send_command(2)
+    /// Like `psr_panel_setting` but only sent if the value changed.
+    pub fn update_psr(&mut self, psr_flags: PSRFlags) -> Result<()> {
+        if self.registers.psr == Some(psr_flags) {
+            return Ok(());
+        }
+        self.psr_panel_setting(psr_flags)
     }
 
     #[allow(dead_code)]
-    pub fn pfs_power_off_sequence_setting(&mut self, pfs_flags: PFSFlags) -> Result<()> {
-        self.connector.send_command(3)?;
-        self.connector.send_data(&[pfs_flags.bits])
+    pub fn pwr_power_setting(&mut self, pwr_flags: PWRFlags) -> Result<()> { //, vdps_lv: u8, vdns_lv: u8) {
+        self.send(Command::PowerSetting(pwr_flags))?;
+        self.registers.pwr = Some(pwr_flags);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn pon_power_on(&mut self) -> Result<()> {
-        self.connector.send_command(4)
+    pub fn update_pwr(&mut self, pwr_flags: PWRFlags) -> Result<()> {
+        if self.registers.pwr == Some(pwr_flags) {
+            return Ok(());
+        }
+        self.pwr_power_setting(pwr_flags)
     }
 
     #[allow(dead_code)]
-    pub fn btst_booster_soft_start(&mut self, pha: u8, phb: u8, phc: u8) ->Result<()> {
-        self.connector.send_command(6)?;
-        self.connector.send_data(&[pha, phb, phc])
+    pub fn pfs_power_off_sequence_setting(&mut self, pfs_flags: PFSFlags) -> Result<()> {
+        self.send(Command::PowerOffSequence(pfs_flags))
     }
 
     #[allow(dead_code)]
-    pub fn dslp_deep_sleep(&mut self) -> Result<()> {
-        self.connector.send_command(7)?;
-        self.connector.send_data(&[0xa5])
+    pub fn btst_booster_soft_start(&mut self, pha: u8, phb: u8, phc: u8) ->Result<()> {
+        self.send(Command::BoosterSoftStart { pha, phb, phc })
     }
 
     #[allow(dead_code)]
-    pub fn drf_display_refresh(&mut self) -> Result<()>{
-        self.connector.send_command(0x12)
+    pub fn pll_control(&mut self, frame_rate: FrameRate) -> Result<()> {
+        self.send(Command::PllControl(frame_rate))?;
+        self.registers.pll = Some(frame_rate);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn pll_control(&mut self, frame_rate_code: u8) -> Result<()> {
-        self.connector.send_command(0x30)?;
-        self.connector.send_data(&[frame_rate_code])
+    pub fn update_pll(&mut self, frame_rate: FrameRate) -> Result<()> {
+        if self.registers.pll == Some(frame_rate) {
+            return Ok(());
+        }
+        self.pll_control(frame_rate)
     }
 
     #[allow(dead_code)]
     pub fn ipc_image_process(&mut self, enabled: bool, line_width: u8) -> Result<()> {
-        self.connector.send_command(0x31)?;
-        self.connector.send_data(&[(enabled as u8) << 4 | (line_width & 3)])
+        check_range(line_width, 3, ConfigError::ValueOutOfRange)?;
+        self.send(Command::ImageProcess { enabled, line_width })
     }
 
     #[allow(dead_code)]
     pub fn tse_temperature_sensor_calibration(&mut self, tse: bool, to: u8) -> Result<()> {
-        self.connector.send_command(0x41)?;
-        self.connector.send_data(&[(tse as u8) << 7 | (to & 0xf)])
+        check_range(to, 0xf, ConfigError::ValueOutOfRange)?;
+        self.send(Command::TemperatureCalibration { tse, to })
     }
 
 
     #[allow(dead_code)]
-    pub fn cdi_vcom_and_data_interval_settings(&mut self, vbd: u8, ddx: bool, cdi: u8) -> Result<()> {
-        self.connector.send_command(0x50)?;
-        self.connector.send_data(&[(vbd & 7) << 5 | (ddx as u8) << 4 | cdi & 0xf])
+    pub fn cdi_vcom_and_data_interval_settings(&mut self, vbd: u8, ddx: bool, cdi: DataInterval) -> Result<()> {
+        check_range(vbd, 7, ConfigError::ValueOutOfRange)?;
+        let setting = DataIntervalSetting { vbd, ddx, interval: cdi };
+        self.send(Command::VcomAndDataInterval(setting))?;
+        self.registers.cdi = Some(setting);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn tcon_setting(&mut self, s2g_g2s: u8) -> Result<()> {
-        self.connector.send_command(0x60)?;
-        self.connector.send_data(&[s2g_g2s])
+    pub fn update_cdi(&mut self, setting: DataIntervalSetting) -> Result<()> {
+        if self.registers.cdi == Some(setting) {
+            return Ok(());
+        }
+        self.cdi_vcom_and_data_interval_settings(setting.vbd, setting.ddx, setting.interval)
     }
 
-    #[allow(dead_code)]
-    pub fn tres_resolution(&mut self, width: u16, height: u16) -> Result<()> {
-        self.connector.send_command(0x61)?;
-        // this doesn't seem to match the spec but it's what the demo code does
-        self.connector.send_data(&[(width >> 8) as u8, (width & 0xff) as u8, (height >> 8) as u8, (height & 0xff) as u8])
+    /// Changes only the border bits of CDI, which has to have been written
+    /// since the last reset.
+    pub fn update_border(&mut self, vbd: u8) -> Result<()> {
+        let cdi = self.registers.cdi.ok_or(ConfigError::NotConfigured)?;
+        self.update_cdi(DataIntervalSetting { vbd, ..cdi })
     }
 
     #[allow(dead_code)]
-    pub fn dam_spi_flash_control(&mut self, dam: bool) -> Result<()> {
-        self.connector.send_command(0x65)?;
-        self.connector.send_data(&[dam as u8])
+    pub fn tcon_setting(&mut self, s2g: NonOverlap, g2s: NonOverlap) -> Result<()> {
+        self.send(Command::TconSetting { s2g, g2s })?;
+        self.registers.tcon = Some((s2g, g2s));
+        Ok(())
+    }
+
+    pub fn update_tcon(&mut self, s2g: NonOverlap, g2s: NonOverlap) -> Result<()> {
+        if self.registers.tcon == Some((s2g, g2s)) {
+            return Ok(());
+        }
+        self.tcon_setting(s2g, g2s)
+    }
+
+    pub fn update_tres(&mut self, width: u16, height: u16) -> Result<()> {
+        if self.registers.tres == Some((width, height)) {
+            return Ok(());
+        }
+        self.tres_resolution(width, height)
+    }
+
+    pub fn update_vcom(&mut self, vv: VcomLevel) -> Result<()> {
+        if self.registers.vcom == Some(vv) {
+            return Ok(());
+        }
+        self.vcom_dc_setting(vv)
     }
 
     #[allow(dead_code)]
-    pub fn vcom_dc_setting(&mut self, vv: u8) -> Result<()> {
-        self.connector.send_command(0x82)?;
-        self.connector.send_data(&[vv])
+    pub fn dam_spi_flash_control(&mut self, dam: bool) -> Result<()> {
+        self.send(Command::SpiFlashControl(dam))
     }
 
     // mentioned in the sample code and on page 21 of the spec
     // but no further explanation
     #[allow(dead_code)]
-    pub(crate) fn define_flash(&mut self, dunno: u8) -> Result<()> {
-        self.connector.send_command(0xe5)?;
-        self.connector.send_data(&[dunno])
+    pub fn define_flash(&mut self, dunno: u8) -> Result<()> {
+        self.send(Command::DefineFlash(dunno))
     }
+}
 
-    #[allow(dead_code)]
-    pub fn transmit(&mut self, data: &[u8]) -> Result<()> {
-        self.connector.send_command(0x10)?;
-        self.connector.send_data(data)
-        //    self.connector.send_command(0x11);
+impl<'a> From<SharedCommand<'a>> for Command<'a> {
+    fn from(command: SharedCommand<'a>) -> Self {
+        match command {
+            SharedCommand::PowerOff => Command::PowerOff,
+            SharedCommand::PowerOn => Command::PowerOn,
+            SharedCommand::DeepSleep => Command::DeepSleep,
+            SharedCommand::DataStartTransmission1(data) => Command::DataStartTransmission(data),
+            SharedCommand::DisplayRefresh => Command::DisplayRefresh,
+            SharedCommand::FlashData => Command::FlashData,
+            SharedCommand::Revision => Command::Revision,
+        }
     }
+}
 
-    #[allow(dead_code)]
-    pub(crate) fn transmit_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
-        self.connector.send_command(0x10)?;
-        self.connector.send_data_with(repeats, source)
+impl<T> UcFamilyController for IL0371<T> where T: DisplayConnector {
+    type Connector = T;
+    type VcomLevel = VcomLevel;
+
+    // reset pulse from the vendor sample code
+    const RESET_LOW_MS: u16 = 10;
+    const RESET_SETTLE_MS: u16 = 10;
+    const BUSY_POLL_MS: u16 = 10;
+
+    fn connector(&mut self) -> &mut T {
+        &mut self.connector
     }
 
+    fn encode_shared(command: SharedCommand) -> Encoded {
+        Command::from(command).encode()
+    }
 
-    #[allow(dead_code)]
-    pub fn flash_data(&mut self) -> Result<()> {
-        self.connector.send_command(0xb9)
+    fn vcom_dc_setting(&mut self, vv: VcomLevel) -> Result<()> {
+        self.send(Command::VcomDc(vv))?;
+        self.registers.vcom = Some(vv);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn await_ready_state(&mut self) -> Result<()> {
-        while self.connector.is_busy()? { self.connector.delay_ms(100)?; }
+    fn tres_resolution(&mut self, width: u16, height: u16) -> Result<()> {
+        self.send(Command::Resolution { width, height })?;
+        self.registers.tres = Some((width, height));
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn forget_registers(&mut self) {
+        self.registers = Registers::default();
+    }
+
+    fn is_busy(&mut self) -> Result<bool> {
+        self.connector.is_busy()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::controller::command::DecodeError;
+    use crate::controller::il0371::{Command, DataIntervalSetting, FrameRate, PSRFlags, PWRFlags};
+    use crate::controller::settings::{ConfigError, DataInterval, NonOverlap};
+
+    #[test]
+    fn test_command_round_trip() {
+        let pixels = [0x33, 0x03];
+        let commands = [
+            Command::PanelSetting(PSRFlags::RES_640_448 | PSRFlags::SHL | PSRFlags::MYSTERY),
+            Command::PowerSetting(PWRFlags::VSOURCE_EN | PWRFlags::VGATE_EN | PWRFlags::VGHL_LVL_18V),
+            Command::BoosterSoftStart { pha: 0xc7, phb: 0xcc, phc: 0x28 },
+            Command::DeepSleep,
+            Command::DataStartTransmission(&pixels),
+            Command::PllControl(FrameRate::from_code(0x3c).unwrap()),
+            Command::TemperatureCalibration { tse: true, to: 5 },
+            Command::VcomAndDataInterval(DataIntervalSetting { vbd: 3, ddx: true, interval: DataInterval::from_code(7).unwrap() }),
+            Command::TconSetting { s2g: NonOverlap::from_code(2).unwrap(), g2s: NonOverlap::from_code(2).unwrap() },
+            Command::Resolution { width: 640, height: 384 },
+            Command::DefineFlash(3),
+            Command::Other(0x40, &[]),
+        ];
+        for command in commands.iter() {
+            let encoded = command.encode();
+            assert_eq!(Command::decode(encoded.opcode(), encoded.params()), Ok(*command));
+        }
+        assert_eq!(Command::Resolution { width: 640, height: 384 }.encode().params(), [0x02, 0x80, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Command::decode(0x61, &[0x02, 0x80, 0x01]), Err(DecodeError::Length));
+        assert_eq!(Command::decode(0x02, &[0x00]), Err(DecodeError::Length));
+        assert_eq!(Command::decode(0x07, &[0x00]), Err(DecodeError::Value(ConfigError::ValueOutOfRange)));
+        assert_eq!(Command::decode(0x30, &[0x40]), Err(DecodeError::Value(ConfigError::FrameRateOutOfRange)));
+        assert_eq!(Command::decode(0x82, &[0x40]), Err(DecodeError::Value(ConfigError::VoltageOutOfRange)));
+    }
+}
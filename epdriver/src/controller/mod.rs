@@ -2,3 +2,10 @@
 pub mod gd7965;
 pub mod il0371;
 pub mod display_connector;
+pub mod settings;
+pub mod uc_family;
+pub mod command;
+pub mod bit_bang;
+pub mod record;
+#[cfg(feature = "std")]
+pub mod remote;
@@ -0,0 +1,522 @@
+//! Recording of everything a connector is asked to do, and replaying it on
+//! another connector.
+//!
+//! A recording starts with `EPDR` and a version byte, followed by events of
+//! a tag byte and its payload, numbers are little endian:
+//!
+//! | tag  | event   | payload                                  |
+//! |------|---------|------------------------------------------|
+//! | 0x01 | reset   | u16 RST low time in ms                   |
+//! | 0x02 | command | u8 opcode                                |
+//! | 0x03 | data    | u32 length, the bytes                    |
+//! | 0x04 | delay   | u16 ms                                   |
+//! | 0x05 | busy    | u8, 1 if BUSY was asserted               |
+//! | 0x06 | read    | u32 length, the bytes that came back     |
+//! | 0x07 | failed  | u8 error code of the preceding operation |
+//! | 0x08 | status  | u8 opcode sent before every BUSY read    |
+//!
+//! A status event comes first in recordings of a controller that only
+//! updates BUSY when asked for its status, like the GD7965.
+
+use core::cell::RefCell;
+
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
+use crate::controller::settings::ConfigError;
+use crate::controller::uc_family::BUSY_TIMEOUT_MS;
+
+pub const MAGIC: [u8; 4] = *b"EPDR";
+pub const VERSION: u8 = 1;
+
+pub(crate) const TAG_RESET: u8 = 0x01;
+pub(crate) const TAG_COMMAND: u8 = 0x02;
+pub(crate) const TAG_DATA: u8 = 0x03;
+pub(crate) const TAG_DELAY: u8 = 0x04;
+pub(crate) const TAG_BUSY: u8 = 0x05;
+pub(crate) const TAG_READ: u8 = 0x06;
+const TAG_FAILED: u8 = 0x07;
+const TAG_STATUS: u8 = 0x08;
+
+// bytes of a send_data_with source recorded and sent at a time
+const DATA_STAGING: usize = 256;
+// replayed reads are done in pieces of this size
+const READ_STAGING: usize = 16;
+// interval BUSY is polled at while replay waits for the panel
+const REPLAY_POLL_MS: u16 = 10;
+
+/// Where a recording goes, with the `std` feature every `std::io::Write`
+/// is one.
+pub trait RecordWrite {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl<W> RecordWrite for W where W: std::io::Write {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        std::io::Write::write_all(self, bytes).map_err(|_e| Error::RecordWriteError)
+    }
+}
+
+/// Records into a caller supplied buffer, fails once it is full.
+pub struct SliceSink<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> SliceSink<'a> {
+        SliceSink { buffer, len: 0 }
+    }
+
+    pub fn written(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl RecordWrite for SliceSink<'_> {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            return Err(Error::RecordWriteError);
+        }
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Code a failed operation is recorded with.
+pub fn error_code(error: &Error) -> u8 {
+    match error {
+        Error::SpiWriteError => 1,
+        Error::SpiReadError => 2,
+        Error::ReadUnsupported => 3,
+        Error::BusyTimeout => 4,
+        Error::DelayError => 5,
+        Error::BusyPinReadError => 6,
+        Error::ResetPinWriteError => 7,
+        Error::DcPinWriteError => 8,
+        Error::CsPinWriteError => 9,
+        Error::BufferSizeError => 10,
+        Error::ConfigError(_) => 11,
+        Error::RecordWriteError => 12,
+        Error::RecordFormatError => 13,
+        Error::RemoteError => 14,
+    }
+}
+
+/// The error an `error_code` stands for, a configuration error needs its
+/// `config_error_code` as well. `None` for codes no error has.
+pub fn error_from_code(code: u8, config_code: u8) -> Option<Error> {
+    let error = match code {
+        1 => Error::SpiWriteError,
+        2 => Error::SpiReadError,
+        3 => Error::ReadUnsupported,
+        4 => Error::BusyTimeout,
+        5 => Error::DelayError,
+        6 => Error::BusyPinReadError,
+        7 => Error::ResetPinWriteError,
+        8 => Error::DcPinWriteError,
+        9 => Error::CsPinWriteError,
+        10 => Error::BufferSizeError,
+        11 => Error::ConfigError(config_error_from_code(config_code)?),
+        12 => Error::RecordWriteError,
+        13 => Error::RecordFormatError,
+        14 => Error::RemoteError,
+        _ => return None,
+    };
+    Some(error)
+}
+
+/// Which configuration error it was, 0 is left for errors that aren't one.
+pub fn config_error_code(error: ConfigError) -> u8 {
+    match error {
+        ConfigError::VoltageOutOfRange => 1,
+        ConfigError::FrameRateOutOfRange => 2,
+        ConfigError::IntervalOutOfRange => 3,
+        ConfigError::OffsetOutOfRange => 4,
+        ConfigError::ValueOutOfRange => 5,
+        ConfigError::ResolutionOutOfRange => 6,
+        ConfigError::UnsupportedMode => 7,
+        ConfigError::NotConfigured => 8,
+    }
+}
+
+/// The configuration error a `config_error_code` stands for.
+pub fn config_error_from_code(code: u8) -> Option<ConfigError> {
+    let error = match code {
+        1 => ConfigError::VoltageOutOfRange,
+        2 => ConfigError::FrameRateOutOfRange,
+        3 => ConfigError::IntervalOutOfRange,
+        4 => ConfigError::OffsetOutOfRange,
+        5 => ConfigError::ValueOutOfRange,
+        6 => ConfigError::ResolutionOutOfRange,
+        7 => ConfigError::UnsupportedMode,
+        8 => ConfigError::NotConfigured,
+        _ => return None,
+    };
+    Some(error)
+}
+
+/// Passes everything on to the wrapped connector and records it, including
+/// the BUSY readings and failures.
+///
+/// The recording is written as it happens, so an unbuffered sink keeps
+/// everything up to a crash.
+pub struct RecordingConnector<C, W> where C: DisplayConnector, W: RecordWrite {
+    connector: C,
+    // is_busy only gets &self
+    sink: RefCell<W>,
+}
+
+impl<C, W> RecordingConnector<C, W> where C: DisplayConnector, W: RecordWrite {
+    pub fn new(connector: C, mut sink: W) -> Result<RecordingConnector<C, W>> {
+        sink.write_all(&MAGIC)?;
+        sink.write_all(&[VERSION])?;
+        Ok(RecordingConnector { connector, sink: RefCell::new(sink) })
+    }
+
+    /// For a controller that has to be sent `command` before BUSY is read,
+    /// so replay can ask again while it waits longer than recorded.
+    pub fn with_status_command(connector: C, sink: W, command: u8) -> Result<RecordingConnector<C, W>> {
+        let recorder = Self::new(connector, sink)?;
+        recorder.record(TAG_STATUS, &[command])?;
+        Ok(recorder)
+    }
+
+    pub fn connector(&self) -> &C {
+        &self.connector
+    }
+
+    pub fn sink(&mut self) -> &mut W {
+        self.sink.get_mut()
+    }
+
+    pub fn release(self) -> (C, W) {
+        (self.connector, self.sink.into_inner())
+    }
+
+    fn record(&self, tag: u8, payload: &[u8]) -> Result<()> {
+        let mut sink = self.sink.borrow_mut();
+        sink.write_all(&[tag])?;
+        sink.write_all(payload)
+    }
+
+    // records the failure of an operation before passing it on
+    fn outcome<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.record(TAG_FAILED, &[error_code(e)])?;
+        }
+        result
+    }
+}
+
+impl<C, W> DisplayConnector for RecordingConnector<C, W> where C: DisplayConnector, W: RecordWrite {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
+        self.record(TAG_RESET, &low_ms.to_le_bytes())?;
+        let result = self.connector.reset(low_ms);
+        self.outcome(result)
+    }
+
+    fn is_busy(&self) -> Result<bool> {
+        let result = self.connector.is_busy();
+        if let Ok(busy) = result {
+            self.record(TAG_BUSY, &[busy as u8])?;
+        }
+        self.outcome(result)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<()> {
+        self.record(TAG_COMMAND, &[command])?;
+        let result = self.connector.send_command(command);
+        self.outcome(result)
+    }
+
+    // every chunk is an event of its own, so a failure part way through
+    // leaves a recording that still parses
+    fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        let mut buffer = [0; DATA_STAGING];
+        let mut start = 0;
+        while start < repeats {
+            let len = (repeats - start).min(DATA_STAGING as u32);
+            for (i, b) in buffer[..len as usize].iter_mut().enumerate() {
+                *b = source(start + i as u32);
+            }
+            self.send_data(&buffer[..len as usize])?;
+            start += len;
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.record(TAG_DATA, &(data.len() as u32).to_le_bytes())?;
+        self.sink.get_mut().write_all(data)?;
+        let result = self.connector.send_data(data);
+        self.outcome(result)
+    }
+
+    fn delay_ms(&mut self, ms: u16) -> Result<()> {
+        self.record(TAG_DELAY, &ms.to_le_bytes())?;
+        let result = self.connector.delay_ms(ms);
+        self.outcome(result)
+    }
+
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let result = self.connector.read_data(buffer);
+        if result.is_ok() {
+            self.record(TAG_READ, &(buffer.len() as u32).to_le_bytes())?;
+            self.sink.get_mut().write_all(buffer)?;
+        }
+        self.outcome(result)
+    }
+}
+
+/// One entry of a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    Reset { low_ms: u16 },
+    Command(u8),
+    Data(&'a [u8]),
+    Delay(u16),
+    Busy(bool),
+    Read(&'a [u8]),
+    /// the operation before failed with this `error_code`
+    Failed(u8),
+    /// the command BUSY has to be asked for with
+    Status(u8),
+}
+
+/// Iterates the events of a recording held in memory, stops at the first
+/// malformed event.
+pub struct Events<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Events<'a> {
+    pub fn new(recording: &'a [u8]) -> Result<Events<'a>> {
+        match recording {
+            [m0, m1, m2, m3, VERSION, events @ ..] if [*m0, *m1, *m2, *m3] == MAGIC => Ok(Events { bytes: events }),
+            _ => Err(Error::RecordFormatError),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(Error::RecordFormatError);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn take_block(&mut self) -> Result<&'a [u8]> {
+        let len = self.take(4)?;
+        self.take(u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+    }
+
+    fn next_event(&mut self) -> Result<Event<'a>> {
+        let event = match self.take(1)?[0] {
+            TAG_RESET => Event::Reset { low_ms: self.take_u16()? },
+            TAG_COMMAND => Event::Command(self.take(1)?[0]),
+            TAG_DATA => Event::Data(self.take_block()?),
+            TAG_DELAY => Event::Delay(self.take_u16()?),
+            TAG_BUSY => match self.take(1)?[0] {
+                0 => Event::Busy(false),
+                1 => Event::Busy(true),
+                _ => return Err(Error::RecordFormatError),
+            },
+            TAG_READ => Event::Read(self.take_block()?),
+            TAG_FAILED => Event::Failed(self.take(1)?[0]),
+            TAG_STATUS => Event::Status(self.take(1)?[0]),
+            _ => return Err(Error::RecordFormatError),
+        };
+        Ok(event)
+    }
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Result<Event<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let event = self.next_event();
+        if event.is_err() {
+            self.bytes = &[];
+        }
+        Some(event)
+    }
+}
+
+/// Sends a recording to `connector` and returns the number of events.
+///
+/// Commands, data, resets and delays are repeated as recorded. Where the
+/// recorded panel was found idle the replay waits for BUSY to clear, so a
+/// slower panel isn't driven ahead of itself, sending the recorded status
+/// command before every extra poll. Reads are repeated but what comes back
+/// is ignored, as are recorded failures.
+pub fn replay<C>(recording: &[u8], connector: &mut C) -> Result<u32> where C: DisplayConnector {
+    let mut status = None;
+    let mut count = 0;
+    for event in Events::new(recording)? {
+        match event? {
+            Event::Reset { low_ms } => connector.reset(low_ms)?,
+            Event::Command(command) => connector.send_command(command)?,
+            Event::Data(data) => connector.send_data(data)?,
+            Event::Delay(ms) => connector.delay_ms(ms)?,
+            Event::Busy(true) => { connector.is_busy()?; }
+            Event::Busy(false) => {
+                let mut waited = 0;
+                while connector.is_busy()? {
+                    if waited >= BUSY_TIMEOUT_MS {
+                        return Err(Error::BusyTimeout);
+                    }
+                    connector.delay_ms(REPLAY_POLL_MS)?;
+                    waited += REPLAY_POLL_MS as u32;
+                    if let Some(command) = status {
+                        connector.send_command(command)?;
+                    }
+                }
+            }
+            Event::Read(data) => {
+                let mut buffer = [0; READ_STAGING];
+                for chunk in data.chunks(READ_STAGING) {
+                    match connector.read_data(&mut buffer[..chunk.len()]) {
+                        Ok(()) | Err(Error::ReadUnsupported) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Event::Failed(_) => {}
+            Event::Status(command) => status = Some(command),
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use crate::controller::display_connector::{DisplayConnector, Error};
+    use crate::controller::gd7965::{opcode, GD7965};
+    use crate::controller::record::{config_error_code, error_code, error_from_code, replay, Event, Events, RecordingConnector, SliceSink};
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::test_kit::MockPanel;
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut buffer = [0u8; 2048];
+        let recorder = RecordingConnector::new(MockPanel::with_busy_reads(2), SliceSink::new(&mut buffer)).unwrap();
+        let mut controller = GD7965::new(recorder);
+        controller.reset().unwrap();
+        controller.pon_power_on().unwrap();
+        controller.await_ready_state().unwrap();
+        controller.transmit_with(300, |i| i as u8).unwrap();
+        controller.tres_resolution(800, 480).unwrap();
+        let recorder = controller.connector();
+        let original = recorder.connector().golden();
+        let recording = recorder.sink().written().to_vec();
+        let recording = &recording[..];
+
+        let events: Vec<Event> = Events::new(recording).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(&events[..3], &[Event::Reset { low_ms: 2 }, Event::Delay(10), Event::Command(0x71)]);
+        assert!(events.contains(&Event::Busy(true)));
+
+        // the bench panel stays busy longer than the recorded one
+        let mut bench = MockPanel::with_busy_reads(6);
+        let count = replay(recording, &mut bench).unwrap();
+        assert_eq!(count as usize, events.len());
+        // a wait is one line however long it took, replay kept polling
+        assert_eq!(bench.golden(), original);
+        assert_eq!(bench.busy_polls(), 6);
+    }
+
+    #[test]
+    fn test_replay_asks_for_status() {
+        let mut buffer = [0u8; 256];
+        let recorder = RecordingConnector::with_status_command(MockPanel::with_busy_reads(1), SliceSink::new(&mut buffer), opcode::FLG).unwrap();
+        let mut controller = GD7965::new(recorder);
+        controller.pon_power_on().unwrap();
+        controller.await_ready_state().unwrap();
+        let recording = controller.connector().sink().written().to_vec();
+        assert_eq!(Events::new(&recording).unwrap().next().unwrap().unwrap(), Event::Status(opcode::FLG));
+
+        // the bench panel is busy for more polls than were recorded, BUSY
+        // has to be asked for again before every one of them
+        let mut bench_buffer = [0u8; 256];
+        let mut bench = RecordingConnector::new(MockPanel::with_busy_reads(4), SliceSink::new(&mut bench_buffer)).unwrap();
+        replay(&recording, &mut bench).unwrap();
+        assert_eq!(bench.connector().busy_polls(), 4);
+        let events: Vec<Event> = Events::new(bench.sink().written()).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(events.iter().filter(|e| matches!(e, Event::Busy(_))).count(), 5);
+        for pair in events.windows(2) {
+            if let [before, Event::Busy(_)] = pair {
+                assert_eq!(*before, Event::Command(opcode::FLG));
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_failure() {
+        let mut buffer = [0u8; 64];
+        let mut panel = MockPanel::default();
+        panel.fail_data(true);
+        let mut recorder = RecordingConnector::new(panel, SliceSink::new(&mut buffer)).unwrap();
+        recorder.send_command(0x10).unwrap();
+        assert!(recorder.send_data(&[1, 2]).is_err());
+        let (_, sink) = recorder.release();
+
+        let events: Vec<Event> = Events::new(sink.written()).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(events, [Event::Command(0x10), Event::Data(&[1, 2]), Event::Failed(error_code(&Error::SpiWriteError))]);
+
+        // only the chunk that was attempted is recorded
+        let mut buffer = [0u8; 512];
+        let mut panel = MockPanel::default();
+        panel.fail_data(true);
+        let mut recorder = RecordingConnector::new(panel, SliceSink::new(&mut buffer)).unwrap();
+        assert!(recorder.send_data_with(300, |i| i as u8).is_err());
+        let (_, sink) = recorder.release();
+
+        let events: Vec<Event> = Events::new(sink.written()).unwrap().map(|e| e.unwrap()).collect();
+        let chunk: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        assert_eq!(events, [Event::Data(&chunk), Event::Failed(error_code(&Error::SpiWriteError))]);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for code in 1..=14 {
+            let config_code = if code == 11 { 7 } else { 0 };
+            let error = error_from_code(code, config_code).unwrap();
+            assert_eq!(error_code(&error), code);
+        }
+        for config_code in 1..=8 {
+            match error_from_code(11, config_code) {
+                Some(Error::ConfigError(config)) => assert_eq!(config_error_code(config), config_code),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!(error_from_code(0, 0).is_none());
+        assert!(error_from_code(15, 0).is_none());
+        assert!(error_from_code(11, 0).is_none());
+        assert!(error_from_code(11, 9).is_none());
+    }
+
+    #[test]
+    fn test_malformed_recordings() {
+        assert!(Events::new(b"EPDQ\x01").is_err());
+        assert!(Events::new(b"EPDR\x02").is_err());
+        let mut events = Events::new(b"EPDR\x01\x02\x10\x03\x05\x00\x00\x00\x01").unwrap();
+        assert_eq!(events.next().unwrap().unwrap(), Event::Command(0x10));
+        assert!(matches!(events.next(), Some(Err(Error::RecordFormatError))));
+        assert!(events.next().is_none());
+
+        let mut buffer = [0u8; 4];
+        assert!(matches!(RecordingConnector::new(MockPanel::default(), SliceSink::new(&mut buffer)), Err(Error::RecordWriteError)));
+    }
+}
@@ -0,0 +1,359 @@
+//! Driving a connector on another machine. `RemoteConnector` forwards every
+//! call over a stream to `serve`, which runs next to the panel and passes it
+//! on to a real connector.
+//!
+//! The client opens with the header of a recording, `EPDR` and the version,
+//! and then sends requests laid out like recorded events, see `record`:
+//!
+//! | tag  | request | payload                | reply after the status    |
+//! |------|---------|------------------------|---------------------------|
+//! | 0x01 | reset   | u16 RST low time in ms |                           |
+//! | 0x02 | command | u8 opcode              |                           |
+//! | 0x03 | data    | u32 length, the bytes  |                           |
+//! | 0x04 | delay   | u16 ms                 |                           |
+//! | 0x05 | busy    |                        | u8, 1 if BUSY is asserted |
+//! | 0x06 | read    | u32 length             | the bytes read            |
+//!
+//! The server answers the header and every request with two status bytes,
+//! zeros on success, otherwise the `record::error_code` of the failure and
+//! for configuration errors its `record::config_error_code`. The rest of a reply only
+//! follows a success.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::vec::Vec;
+
+use core::cell::RefCell;
+
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
+use crate::controller::record::{config_error_code, error_code, error_from_code, MAGIC, VERSION, TAG_BUSY, TAG_COMMAND, TAG_DATA, TAG_DELAY, TAG_READ, TAG_RESET};
+
+// bytes of data sent or forwarded at a time
+const DATA_STAGING: usize = 4096;
+/// Longest read a server carries out, longer ones fail with `BufferSizeError`.
+pub const MAX_READ: usize = 4096;
+
+const OK: [u8; 2] = [0, 0];
+
+fn status<T>(result: &Result<T>) -> [u8; 2] {
+    match result {
+        Ok(_) => OK,
+        Err(e @ Error::ConfigError(config)) => [error_code(e), config_error_code(*config)],
+        Err(e) => [error_code(e), 0],
+    }
+}
+
+fn check_status(status: [u8; 2]) -> Result<()> {
+    match status {
+        OK => Ok(()),
+        // unknown codes come from a server that doesn't speak our version
+        [code, config_code] => Err(error_from_code(code, config_code).unwrap_or(Error::RemoteError)),
+    }
+}
+
+fn link<T>(result: io::Result<T>) -> Result<T> {
+    result.map_err(|_e| Error::RemoteError)
+}
+
+/// `DisplayConnector` at the other end of a stream, usually a `TcpStream`
+/// or `UnixStream` connected to `epdriver-server`.
+///
+/// Every call waits for the server's answer. Errors of the remote connector
+/// come back as they are, a broken connection as `Error::RemoteError`. The
+/// stream's timeouts apply, so set them before `new` when a hung server
+/// shouldn't block forever.
+pub struct RemoteConnector<S> where S: Read + Write {
+    // is_busy only gets &self
+    stream: RefCell<BufWriter<S>>,
+}
+
+impl<S> RemoteConnector<S> where S: Read + Write {
+    /// Greets the server on `stream` and checks it speaks the same version.
+    pub fn new(stream: S) -> Result<RemoteConnector<S>> {
+        let connector = RemoteConnector { stream: RefCell::new(BufWriter::new(stream)) };
+        connector.send(&MAGIC)?;
+        connector.send(&[VERSION])?;
+        connector.finish(&mut [])?;
+        Ok(connector)
+    }
+
+    fn send(&self, bytes: &[u8]) -> Result<()> {
+        link(self.stream.borrow_mut().write_all(bytes))
+    }
+
+    // flushes the request, waits for its status and fills `reply`
+    fn finish(&self, reply: &mut [u8]) -> Result<()> {
+        let mut stream = self.stream.borrow_mut();
+        link(stream.flush())?;
+        let stream = stream.get_mut();
+        let mut status = [0; 2];
+        link(stream.read_exact(&mut status))?;
+        check_status(status)?;
+        link(stream.read_exact(reply))
+    }
+}
+
+impl RemoteConnector<TcpStream> {
+    /// Connects to a server listening on TCP.
+    pub fn connect_tcp<A>(addr: A) -> Result<RemoteConnector<TcpStream>> where A: ToSocketAddrs {
+        let stream = link(TcpStream::connect(addr))?;
+        // requests are small and each of them waits for its reply anyway
+        link(stream.set_nodelay(true))?;
+        Self::new(stream)
+    }
+}
+
+#[cfg(unix)]
+impl RemoteConnector<UnixStream> {
+    /// Connects to a server listening on a Unix socket.
+    pub fn connect_unix<P>(path: P) -> Result<RemoteConnector<UnixStream>> where P: AsRef<Path> {
+        Self::new(link(UnixStream::connect(path))?)
+    }
+}
+
+impl<S> DisplayConnector for RemoteConnector<S> where S: Read + Write {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
+        self.send(&[TAG_RESET])?;
+        self.send(&low_ms.to_le_bytes())?;
+        self.finish(&mut [])
+    }
+
+    fn is_busy(&self) -> Result<bool> {
+        self.send(&[TAG_BUSY])?;
+        let mut busy = [0];
+        self.finish(&mut busy)?;
+        match busy[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::RemoteError),
+        }
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<()> {
+        self.send(&[TAG_COMMAND, command])?;
+        self.finish(&mut [])
+    }
+
+    fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        self.send(&[TAG_DATA])?;
+        self.send(&repeats.to_le_bytes())?;
+        let mut buffer = [0; DATA_STAGING];
+        let mut start = 0;
+        while start < repeats {
+            let len = (repeats - start).min(DATA_STAGING as u32);
+            for (i, b) in buffer[..len as usize].iter_mut().enumerate() {
+                *b = source(start + i as u32);
+            }
+            self.send(&buffer[..len as usize])?;
+            start += len;
+        }
+        self.finish(&mut [])
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.send(&[TAG_DATA])?;
+        self.send(&(data.len() as u32).to_le_bytes())?;
+        self.send(data)?;
+        self.finish(&mut [])
+    }
+
+    fn delay_ms(&mut self, ms: u16) -> Result<()> {
+        self.send(&[TAG_DELAY])?;
+        self.send(&ms.to_le_bytes())?;
+        self.finish(&mut [])
+    }
+
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.send(&[TAG_READ])?;
+        self.send(&(buffer.len() as u32).to_le_bytes())?;
+        self.finish(buffer)
+    }
+}
+
+/// Carries out the requests of one `RemoteConnector` on `connector` until
+/// the client hangs up, returns the number of requests.
+///
+/// Failures of `connector` are passed back to the client and serving goes
+/// on. A broken connection, a client of another version or a malformed
+/// request end it with `Error::RemoteError`.
+pub fn serve<C, S>(connector: &mut C, stream: S) -> Result<u32> where C: DisplayConnector, S: Read + Write {
+    let mut stream = BufReader::new(stream);
+    let mut header = [0; 5];
+    link(stream.read_exact(&mut header))?;
+    if header[..4] != MAGIC || header[4] != VERSION {
+        reply(&mut stream, &Err::<(), _>(Error::RecordFormatError), &[])?;
+        return Err(Error::RemoteError);
+    }
+    reply(&mut stream, &Ok(()), &[])?;
+
+    let mut count = 0;
+    loop {
+        let mut tag = [0];
+        match stream.read(&mut tag) {
+            Ok(0) => return Ok(count),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(Error::RemoteError),
+        }
+        match tag[0] {
+            TAG_RESET => {
+                let low_ms = read_u16(&mut stream)?;
+                reply(&mut stream, &connector.reset(low_ms), &[])?;
+            }
+            TAG_COMMAND => {
+                let mut command = [0];
+                link(stream.read_exact(&mut command))?;
+                reply(&mut stream, &connector.send_command(command[0]), &[])?;
+            }
+            TAG_DATA => {
+                let len = read_u32(&mut stream)?;
+                let result = forward_data(connector, &mut stream, len)?;
+                reply(&mut stream, &result, &[])?;
+            }
+            TAG_DELAY => {
+                let ms = read_u16(&mut stream)?;
+                reply(&mut stream, &connector.delay_ms(ms), &[])?;
+            }
+            TAG_BUSY => {
+                let result = connector.is_busy();
+                let busy = matches!(result, Ok(true));
+                reply(&mut stream, &result, &[busy as u8])?;
+            }
+            TAG_READ => {
+                let len = read_u32(&mut stream)? as usize;
+                if len > MAX_READ {
+                    reply(&mut stream, &Err::<(), _>(Error::BufferSizeError), &[])?;
+                } else {
+                    let mut buffer = vec![0; len];
+                    let result = connector.read_data(&mut buffer);
+                    reply(&mut stream, &result, &buffer)?;
+                }
+            }
+            _ => return Err(Error::RemoteError),
+        }
+        count += 1;
+    }
+}
+
+fn read_u16<S>(stream: &mut BufReader<S>) -> Result<u16> where S: Read {
+    let mut bytes = [0; 2];
+    link(stream.read_exact(&mut bytes))?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<S>(stream: &mut BufReader<S>) -> Result<u32> where S: Read {
+    let mut bytes = [0; 4];
+    link(stream.read_exact(&mut bytes))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// passes `len` bytes of the stream on in pieces, the outer result is the
+// connection's and the inner one the connector's. After a failure the rest
+// is still read so the next request starts in the right place.
+fn forward_data<C, S>(connector: &mut C, stream: &mut BufReader<S>, len: u32) -> Result<Result<()>> where C: DisplayConnector, S: Read {
+    let mut buffer = [0; DATA_STAGING];
+    let mut result = Ok(());
+    let mut left = len as usize;
+    while left > 0 {
+        let piece = left.min(DATA_STAGING);
+        link(stream.read_exact(&mut buffer[..piece]))?;
+        if result.is_ok() {
+            result = connector.send_data(&buffer[..piece]);
+        }
+        left -= piece;
+    }
+    Ok(result)
+}
+
+fn reply<S, T>(stream: &mut BufReader<S>, result: &Result<T>, payload: &[u8]) -> Result<()> where S: Read + Write {
+    let mut message = Vec::with_capacity(2 + payload.len());
+    message.extend_from_slice(&status(result));
+    if result.is_ok() {
+        message.extend_from_slice(payload);
+    }
+    link(stream.get_mut().write_all(&message))?;
+    link(stream.get_mut().flush())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::string::String;
+    use std::thread;
+    use crate::controller::display_connector::{DisplayConnector, Error};
+    use crate::controller::gd7965::GD7965;
+    use crate::controller::remote::{serve, RemoteConnector};
+    use crate::controller::settings::ConfigError;
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::test_kit::MockPanel;
+
+    // BUSY reads busy twice after power on and refresh, command 0xff is refused
+    fn panel() -> MockPanel {
+        let mut panel = MockPanel::with_busy_reads(2);
+        panel.fail_on(Some(0xff));
+        panel.fail_with(|| ConfigError::UnsupportedMode.into());
+        panel
+    }
+
+    fn drive<C>(connector: C) -> GD7965<C> where C: DisplayConnector {
+        let mut controller = GD7965::new(connector);
+        controller.reset().unwrap();
+        controller.pon_power_on().unwrap();
+        controller.await_ready_state().unwrap();
+        controller.transmit_with(10_000, |i| (i * 7) as u8).unwrap();
+        controller.tres_resolution(800, 480).unwrap();
+        controller.connector().send_command(0x12).unwrap();
+        controller
+    }
+
+    #[test]
+    fn test_remote_matches_local() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut panel = panel();
+            let count = serve(&mut panel, server);
+            (panel, count)
+        });
+
+        let mut controller = drive(RemoteConnector::new(client).unwrap());
+        let remote = controller.connector();
+        let mut read = [0; 3];
+        remote.read_data(&mut read).unwrap();
+        assert_eq!(read, [0xa0, 0xa1, 0xa2]);
+        assert!(matches!(remote.send_command(0xff), Err(Error::ConfigError(ConfigError::UnsupportedMode))));
+        drop(controller);
+
+        let (served, count) = handle.join().unwrap();
+        let mut local = drive(panel());
+        let mut golden = local.connector().golden();
+        golden.push(String::from("read 3"));
+        assert_eq!(served.golden(), golden);
+        assert!(count.unwrap() as usize > golden.len());
+    }
+
+    #[test]
+    fn test_broken_connections() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || serve(&mut panel(), server));
+        client.write_all(b"EPDR\x02").unwrap();
+        let mut status = [0; 2];
+        client.read_exact(&mut status).unwrap();
+        assert_eq!(status, [13, 0]);
+        assert!(matches!(handle.join().unwrap(), Err(Error::RemoteError)));
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || serve(&mut panel(), server));
+        let mut remote = RemoteConnector::new(client).unwrap();
+        remote.send_command(0x12).unwrap();
+        // a request the server doesn't know ends the session
+        remote.send(&[0x42]).unwrap();
+        assert!(matches!(remote.finish(&mut []), Err(Error::RemoteError)));
+        assert!(matches!(handle.join().unwrap(), Err(Error::RemoteError)));
+        assert!(matches!(remote.is_busy(), Err(Error::RemoteError)));
+    }
+}
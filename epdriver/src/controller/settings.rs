@@ -0,0 +1,64 @@
+use core::result;
+
+/// Returned when a setting is outside the range a controller accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    VoltageOutOfRange,
+    FrameRateOutOfRange,
+    IntervalOutOfRange,
+    OffsetOutOfRange,
+    ValueOutOfRange,
+    ResolutionOutOfRange,
+    /// the combination of settings isn't supported by the controller
+    UnsupportedMode,
+    /// the register has to be written before it can be partially changed
+    NotConfigured,
+}
+
+pub type ConfigResult<T> = result::Result<T, ConfigError>;
+
+pub(crate) fn check_range(value: u8, max: u8, error: ConfigError) -> ConfigResult<u8> {
+    if value <= max { Ok(value) } else { Err(error) }
+}
+
+/// VCOM and data interval, 0 is the longest (17 frames) and 15 the shortest (2 frames).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataInterval(pub(crate) u8);
+
+impl DataInterval {
+    pub fn from_code(code: u8) -> ConfigResult<DataInterval> {
+        check_range(code, 0xf, ConfigError::IntervalOutOfRange).map(DataInterval)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+/// Source-to-gate or gate-to-source non-overlap period, 0 is 4us and each
+/// step adds another 4us up to 15 (64us).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonOverlap(pub(crate) u8);
+
+impl NonOverlap {
+    pub fn from_code(code: u8) -> ConfigResult<NonOverlap> {
+        check_range(code, 0xf, ConfigError::IntervalOutOfRange).map(NonOverlap)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::settings::{ConfigError, DataInterval, NonOverlap};
+
+    #[test]
+    fn test_interval_ranges() {
+        assert_eq!(DataInterval::from_code(7).unwrap().code(), 7);
+        assert_eq!(DataInterval::from_code(16), Err(ConfigError::IntervalOutOfRange));
+        assert_eq!(NonOverlap::from_code(15).unwrap().code(), 15);
+        assert_eq!(NonOverlap::from_code(0x22), Err(ConfigError::IntervalOutOfRange));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::controller::command::Encoded;
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
+
+/// Longest a tri-colour refresh is allowed to keep BUSY asserted.
+pub const BUSY_TIMEOUT_MS: u32 = 60_000;
+
+// the controller loads its OTP after a reset
+const RESET_TIMEOUT_MS: u32 = 1000;
+
+// how long the self test waits for BUSY to change
+const SELF_TEST_POWER_ON_TIMEOUT_MS: u32 = 1000;
+const SELF_TEST_BUSY_POLLS: u32 = 20;
+
+/// Opcodes both controllers share.
+pub mod opcode {
+    pub const POF: u8 = 0x02;
+    pub const PON: u8 = 0x04;
+    pub const DSLP: u8 = 0x07;
+    pub const DTM1: u8 = 0x10;
+    pub const DRF: u8 = 0x12;
+    pub const TRES: u8 = 0x61;
+    pub const REV: u8 = 0x70;
+    pub const VDCS: u8 = 0x82;
+    pub const FLASH: u8 = 0xb9;
+
+    /// deep sleep is only entered with this check code
+    pub const DEEP_SLEEP_CHECK: u8 = 0xa5;
+}
+
+/// How the BUSY line behaved during `self_test`, BUSY is active low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyState {
+    /// went busy on power on and came back
+    Ok,
+    /// never left the busy state, controller missing or not powered
+    StuckLow,
+    /// never went busy, BUSY not connected or the commands don't arrive
+    StuckHigh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub present: bool,
+    pub busy: BusyState,
+    /// `None` if the connector can't read or nothing sensible came back
+    pub revision: Option<[u8; 3]>,
+}
+
+/// The commands the default methods of `UcFamilyController` send, each
+/// controller encodes them with its own `Command`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedCommand<'a> {
+    PowerOff,
+    PowerOn,
+    DeepSleep,
+    DataStartTransmission1(&'a [u8]),
+    DisplayRefresh,
+    FlashData,
+    Revision,
+}
+
+/// Commands shared by the UltraChip family controllers (IL0371/UC8159C and
+/// GD7965/UC8179), panels only using these can be written generically.
+pub trait UcFamilyController {
+    type Connector: DisplayConnector;
+    /// VCOM DC level, the valid range differs between controllers
+    type VcomLevel;
+
+    /// How long RST has to be held low.
+    const RESET_LOW_MS: u16;
+    /// Time after reset before BUSY can be trusted.
+    const RESET_SETTLE_MS: u16;
+    /// Interval BUSY is polled at, a coarse interval wastes up to this
+    /// much time after every busy phase.
+    const BUSY_POLL_MS: u16;
+
+    fn connector(&mut self) -> &mut Self::Connector;
+
+    /// The controller's own encoding of a shared command.
+    fn encode_shared(command: SharedCommand) -> Encoded;
+
+    fn send_shared(&mut self, command: SharedCommand) -> Result<()> {
+        Self::encode_shared(command).send(self.connector())
+    }
+
+    fn vcom_dc_setting(&mut self, vcom: Self::VcomLevel) -> Result<()>;
+
+    fn tres_resolution(&mut self, width: u16, height: u16) -> Result<()>;
+
+    /// Drops the shadow copies of the registers, called by `reset` as the
+    /// controller is back at its defaults afterwards.
+    fn forget_registers(&mut self);
+
+    fn is_busy(&mut self) -> Result<bool>;
+
+    fn await_ready_state(&mut self) -> Result<()> {
+        self.wait_while_busy(Self::BUSY_POLL_MS, BUSY_TIMEOUT_MS)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.forget_registers();
+        self.connector().reset(Self::RESET_LOW_MS)?;
+        self.connector().delay_ms(Self::RESET_SETTLE_MS)?;
+        self.wait_while_busy(Self::BUSY_POLL_MS, RESET_TIMEOUT_MS)
+    }
+
+    fn pof_power_off(&mut self) -> Result<()> {
+        self.send_shared(SharedCommand::PowerOff)
+    }
+
+    fn pon_power_on(&mut self) -> Result<()> {
+        self.send_shared(SharedCommand::PowerOn)
+    }
+
+    fn dslp_deep_sleep(&mut self) -> Result<()> {
+        self.send_shared(SharedCommand::DeepSleep)
+    }
+
+    fn drf_display_refresh(&mut self) -> Result<()> {
+        self.send_shared(SharedCommand::DisplayRefresh)
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> Result<()> {
+        self.send_shared(SharedCommand::DataStartTransmission1(data))
+    }
+
+    // only the opcode, the data is streamed from `source`
+    fn transmit_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        let dtm1 = Self::encode_shared(SharedCommand::DataStartTransmission1(&[]));
+        self.connector().send_command(dtm1.opcode())?;
+        self.connector().send_data_with(repeats, source)
+    }
+
+    fn flash_data(&mut self) -> Result<()> {
+        self.send_shared(SharedCommand::FlashData)
+    }
+
+    // LUT revision followed by the chip revision
+    fn rev_revision(&mut self) -> Result<[u8; 3]> {
+        let mut revision = [0; 3];
+        self.send_shared(SharedCommand::Revision)?;
+        self.connector().read_data(&mut revision)?;
+        Ok(revision)
+    }
+
+    /// Polls BUSY every `poll_ms` until it clears, fails with
+    /// `Error::BusyTimeout` after `timeout_ms`.
+    fn wait_while_busy(&mut self, poll_ms: u16, timeout_ms: u32) -> Result<()> {
+        let mut waited = 0;
+        while self.is_busy()? {
+            if waited >= timeout_ms {
+                return Err(Error::BusyTimeout);
+            }
+            self.connector().delay_ms(poll_ms)?;
+            waited += poll_ms.max(1) as u32;
+        }
+        Ok(())
+    }
+
+    /// Resets the controller and checks BUSY goes through power on as
+    /// expected, the controller has to be initialised again afterwards.
+    fn self_test(&mut self) -> Result<SelfTestReport> {
+        // a reset controller is idle once it has loaded its OTP
+        let busy = match self.reset() {
+            Err(Error::BusyTimeout) => BusyState::StuckLow,
+            Err(e) => return Err(e),
+            Ok(()) => {
+                self.pon_power_on()?;
+                // power on only keeps BUSY low for a few ms
+                let mut went_busy = false;
+                for _ in 0..SELF_TEST_BUSY_POLLS {
+                    if self.is_busy()? {
+                        went_busy = true;
+                        break;
+                    }
+                    self.connector().delay_ms(1)?;
+                }
+                if !went_busy {
+                    BusyState::StuckHigh
+                } else {
+                    match self.wait_while_busy(10, SELF_TEST_POWER_ON_TIMEOUT_MS) {
+                        Err(Error::BusyTimeout) => BusyState::StuckLow,
+                        Err(e) => return Err(e),
+                        Ok(()) => BusyState::Ok,
+                    }
+                }
+            }
+        };
+        if busy == BusyState::Ok {
+            self.pof_power_off()?;
+            self.wait_while_busy(10, SELF_TEST_POWER_ON_TIMEOUT_MS)?;
+        }
+
+        let revision = match self.rev_revision() {
+            // a floating or unconnected data line reads all zeros or all ones
+            Ok(revision) if revision.iter().any(|&b| b != 0 && b != 0xff) => Some(revision),
+            Ok(_) | Err(Error::ReadUnsupported) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(SelfTestReport { present: busy == BusyState::Ok, busy, revision })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use crate::controller::display_connector::{DisplayConnector, Error, Result};
+    use crate::controller::gd7965::GD7965;
+    use crate::controller::il0371::IL0371;
+    use crate::controller::uc_family::{BusyState, SelfTestReport, UcFamilyController};
+    use crate::test_kit::MockPanel;
+
+    fn common_commands<C>(controller: &mut C) where C: UcFamilyController<Connector = MockPanel> {
+        controller.pon_power_on().unwrap();
+        controller.tres_resolution(640, 384).unwrap();
+        controller.transmit(&[0x33]).unwrap();
+        controller.transmit_with(2, |i| i as u8).unwrap();
+        controller.drf_display_refresh().unwrap();
+        controller.pof_power_off().unwrap();
+        controller.dslp_deep_sleep().unwrap();
+        controller.flash_data().unwrap();
+
+        assert_eq!(controller.connector().golden(), ["04", "61 02 80 01 80", "10 33", "10 00 01", "12", "02", "07 a5", "b9"]);
+    }
+
+    #[test]
+    fn test_common_commands() {
+        common_commands(&mut IL0371::new(MockPanel::default()));
+        common_commands(&mut GD7965::new(MockPanel::default()));
+    }
+
+    // BUSY reads as busy for reads busy_from..busy_until after power on
+    struct BusyScript {
+        busy_from: u32,
+        busy_until: u32,
+        powered_on: bool,
+        reads: Cell<u32>,
+        revision: Option<[u8; 3]>,
+    }
+
+    impl BusyScript {
+        fn new(busy_from: u32, busy_until: u32, revision: Option<[u8; 3]>) -> BusyScript {
+            BusyScript { busy_from, busy_until, powered_on: false, reads: Cell::new(0), revision }
+        }
+    }
+
+    impl DisplayConnector for BusyScript {
+        fn reset(&mut self, _low_ms: u16) -> Result<()> {
+            Ok(())
+        }
+
+        fn is_busy(&self) -> Result<bool> {
+            if !self.powered_on {
+                return Ok(self.busy_from == 0);
+            }
+            let read = self.reads.get();
+            self.reads.set(read + 1);
+            Ok(read >= self.busy_from && read < self.busy_until)
+        }
+
+        fn send_command(&mut self, command: u8) -> Result<()> {
+            if command == 0x04 {
+                self.powered_on = true;
+            }
+            Ok(())
+        }
+
+        fn send_data_with<F>(&mut self, _repeats: u32, _source: F) -> Result<()> where F: Fn(u32) -> u8 {
+            Ok(())
+        }
+
+        fn send_data(&mut self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn delay_ms(&mut self, _ms: u16) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+            let revision = self.revision.ok_or(Error::ReadUnsupported)?;
+            buffer.copy_from_slice(&revision);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_self_test() {
+        let report = IL0371::new(BusyScript::new(2, 5, Some([0x00, 0x0a, 0x01]))).self_test().unwrap();
+        assert_eq!(report, SelfTestReport { present: true, busy: BusyState::Ok, revision: Some([0x00, 0x0a, 0x01]) });
+
+        let report = GD7965::new(BusyScript::new(2, 5, Some([0xff; 3]))).self_test().unwrap();
+        assert_eq!(report, SelfTestReport { present: true, busy: BusyState::Ok, revision: None });
+
+        let report = IL0371::new(BusyScript::new(0, u32::MAX, None)).self_test().unwrap();
+        assert_eq!(report.busy, BusyState::StuckLow);
+
+        let report = IL0371::new(BusyScript::new(1, u32::MAX, None)).self_test().unwrap();
+        assert_eq!(report.busy, BusyState::StuckLow);
+
+        let report = GD7965::new(BusyScript::new(u32::MAX, u32::MAX, None)).self_test().unwrap();
+        assert_eq!(report, SelfTestReport { present: false, busy: BusyState::StuckHigh, revision: None });
+    }
+}
@@ -6,75 +6,80 @@ pub enum DisplayError {
 
 }
 
-pub trait EPaperDisplay {
-    fn init(&mut self) -> Result<()>;
-    fn push_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8;
-    fn clear(&mut self) -> Result<()>;
-    fn width(&self) -> u16;
-    fn height(&self) -> u16;
-
+/// What the panel drives onto the border around the active area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Border {
+    White,
+    Black,
+    Accent,
+    /// border electrode is not driven at all
+    Floating,
 }
-#[cfg(test)]
-mod tests {
-    use embedded_hal::blocking::spi::{Write, Transfer};
-    use embedded_hal::blocking::delay::DelayMs;
-    use embedded_hal::digital::v2::{ InputPin, OutputPin };
-
-    struct MockPin {
-        name: &'static str,
-        state: bool,
-    }
-
-    impl OutputPin for MockPin {
-        type Error = ();
-
-        fn set_low(&mut self) -> Result<(), Self::Error> {
-            self.state = false;
-            Result::Ok(())
-        }
 
-        fn set_high(&mut self) -> Result<(), Self::Error> {
-            self.state = true;
-            Result::Ok(())
-        }
-    }
+/// Waveform used to refresh a tri-colour panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshMode {
+    /// black, white and accent, slow
+    Colour,
+    /// black and white only, accent pixels are shown black.
+    /// Not every controller has a black/white mode, the IL0371 doesn't.
+    Fast,
+}
 
-    impl InputPin for MockPin {
-        type Error = ();
+/// Rectangular part of the panel, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
 
-        fn is_high(&self) -> Result<bool, Self::Error> {
-            Result::Ok(self.state)
-        }
+// black, white, accent, white
+const DEEP_CLEAN_SEQUENCE: [u8; 4] = [0, 2, 1, 2];
 
-        fn is_low(&self) -> Result<bool, Self::Error> {
-            Result::Ok(!self.state)
-        }
+pub trait EPaperDisplay {
+    fn init(&mut self) -> Result<()>;
+    /// takes effect on the next call to `init`
+    fn set_border(&mut self, border: Border);
+    fn push_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.start_image_with(source)?;
+        self.wait()
     }
 
+    /// Transfers the frame and triggers the refresh but returns without
+    /// waiting for the refresh to finish.
+    fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8;
 
-    struct MockSpi {}
+    /// Whether the refresh started by `start_image_with` is still running.
+    fn is_refreshing(&mut self) -> Result<bool>;
 
-    impl Write<u8> for MockSpi {
-        type Error = ();
+    /// Blocks until the running refresh has finished and completes the
+    /// power sequence, returns straight away if nothing is refreshing.
+    fn wait(&mut self) -> Result<()>;
 
-        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
-            Result::Ok(())
-        }
+    /// Refreshes only `window`, `source` is called with panel coordinates.
+    /// Panels without partial refresh push the whole frame, so `source` has
+    /// to be valid for every pixel.
+    fn push_window_with<F>(&mut self, window: Window, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        let _ = window;
+        self.push_image_with(source)
     }
 
-    impl Transfer<u8> for MockSpi {
-        type Error = ();
+    fn supports_partial_refresh(&self) -> bool {
+        false
+    }
 
-        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
-            Result::Ok(&[])
+    /// Cycles full black, white and accent frames to clear ghosting,
+    /// the panel is left white.
+    fn deep_clean(&mut self) -> Result<()> {
+        for &val in DEEP_CLEAN_SEQUENCE.iter() {
+            self.push_image_with(|_, _| val)?;
         }
+        Ok(())
     }
+    fn clear(&mut self) -> Result<()>;
+    fn width(&self) -> u16;
+    fn height(&self) -> u16;
 
-    struct MockDelay {}
-
-    impl DelayMs<u16> for MockDelay {
-        fn delay_ms(&mut self, ms: u16) {}
-    }
 }
-
-
@@ -0,0 +1,206 @@
+use crate::controller::display_connector::{Error, Result};
+use crate::controller::settings::ConfigError;
+
+// the current row and the two rows Atkinson spreads the error into
+const ERROR_ROWS: usize = 3;
+
+/// A colour the panel can show and the pixel value that selects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteEntry {
+    pub rgb: [u8; 3],
+    pub value: u8,
+}
+
+pub const BLACK_WHITE_PALETTE: [PaletteEntry; 2] = [
+    PaletteEntry { rgb: [0x00, 0x00, 0x00], value: 0 },
+    PaletteEntry { rgb: [0xff, 0xff, 0xff], value: 2 },
+];
+
+/// Black, accent and white roughly as they look on the tri-colour glass.
+pub const TRI_COLOUR_PALETTE: [PaletteEntry; 3] = [
+    PaletteEntry { rgb: [0x00, 0x00, 0x00], value: 0 },
+    PaletteEntry { rgb: [0x93, 0x78, 0x00], value: 1 },
+    PaletteEntry { rgb: [0xff, 0xff, 0xff], value: 2 },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    /// spreads all of the error over the next pixel and row
+    FloydSteinberg,
+    /// spreads 3/4 of the error over two rows, more contrast and less noise
+    Atkinson,
+}
+
+impl Kernel {
+    // (dx, dy, weight) and the sum of the weights of a full error
+    fn taps(&self) -> (&'static [(i32, u32, i32)], i32) {
+        match self {
+            Kernel::FloydSteinberg => (&[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)], 16),
+            Kernel::Atkinson => (&[(1, 0, 1), (2, 0, 1), (-1, 1, 1), (0, 1, 1), (1, 1, 1), (0, 2, 1)], 8),
+        }
+    }
+}
+
+/// Length of the error buffer `RowDitherer` needs for `width` pixels.
+pub fn error_buffer_len(width: u16) -> usize {
+    ERROR_ROWS * width as usize
+}
+
+/// Error diffusion ditherer that takes an image one RGB row at a time, so
+/// a frame never has to be held in full colour.
+///
+/// The error buffer is supplied by the caller and has to be at least
+/// `error_buffer_len(width)` entries long. Rows come out as pixel values of
+/// the palette, ready for `PackedPlanes::set_pixel` and friends.
+pub struct RowDitherer<'a> {
+    width: u16,
+    palette: &'a [PaletteEntry],
+    kernel: Kernel,
+    errors: &'a mut [[i16; 3]],
+    row: u32,
+}
+
+impl<'a> RowDitherer<'a> {
+    pub fn new(width: u16, palette: &'a [PaletteEntry], kernel: Kernel, errors: &'a mut [[i16; 3]]) -> Result<RowDitherer<'a>> {
+        if palette.is_empty() {
+            return Err(ConfigError::ValueOutOfRange.into());
+        }
+        let size = error_buffer_len(width);
+        if errors.len() < size {
+            return Err(Error::BufferSizeError);
+        }
+        let mut ditherer = RowDitherer { width, palette, kernel, errors: &mut errors[..size], row: 0 };
+        ditherer.reset();
+        Ok(ditherer)
+    }
+
+    /// Starts a new frame.
+    pub fn reset(&mut self) {
+        self.errors.iter_mut().for_each(|e| *e = [0; 3]);
+        self.row = 0;
+    }
+
+    /// Rows dithered since the last reset.
+    pub fn row(&self) -> u32 {
+        self.row
+    }
+
+    /// Dithers the next row, `rgb` holds `width` RGB888 pixels and `sink` is
+    /// called with the x position and pixel value of each of them.
+    pub fn dither_row_with<F>(&mut self, rgb: &[u8], mut sink: F) -> Result<()> where F: FnMut(u32, u8) {
+        let width = self.width as usize;
+        if rgb.len() < width * 3 {
+            return Err(Error::BufferSizeError);
+        }
+        let (taps, total) = self.kernel.taps();
+        let current = self.slot(0);
+        for x in 0..width {
+            let carried = self.errors[current + x];
+            let wanted = [0, 1, 2].map(|c| (rgb[x * 3 + c] as i16 + carried[c]).clamp(0, 255));
+            let entry = self.nearest(wanted);
+            sink(x as u32, entry.value);
+            let error = [0, 1, 2].map(|c| (wanted[c] - entry.rgb[c] as i16) as i32);
+
+            for &(dx, dy, weight) in taps {
+                let tx = x as i32 + dx;
+                if tx < 0 || tx >= width as i32 {
+                    continue;
+                }
+                let target = self.slot(dy) + tx as usize;
+                for (diffused, error) in self.errors[target].iter_mut().zip(error.iter()) {
+                    *diffused += (error * weight / total) as i16;
+                }
+            }
+        }
+        // the row becomes the one two ahead
+        self.errors[current..current + width].iter_mut().for_each(|e| *e = [0; 3]);
+        self.row += 1;
+        Ok(())
+    }
+
+    /// Like `dither_row_with` but writes the pixel values to `out`, which
+    /// has to hold at least `width` values.
+    pub fn dither_row(&mut self, rgb: &[u8], out: &mut [u8]) -> Result<()> {
+        if out.len() < self.width as usize {
+            return Err(Error::BufferSizeError);
+        }
+        self.dither_row_with(rgb, |x, val| out[x as usize] = val)
+    }
+
+    // offset of the error row dy rows below the current one
+    fn slot(&self, dy: u32) -> usize {
+        ((self.row + dy) as usize % ERROR_ROWS) * self.width as usize
+    }
+
+    fn nearest(&self, rgb: [i16; 3]) -> PaletteEntry {
+        let distance = |entry: &&PaletteEntry| -> i32 {
+            (0..3).map(|c| {
+                let d = (rgb[c] - entry.rgb[c] as i16) as i32;
+                d * d
+            }).sum()
+        };
+        *self.palette.iter().min_by_key(distance).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::display_connector::Error;
+    use crate::dither::{error_buffer_len, Kernel, RowDitherer, BLACK_WHITE_PALETTE, TRI_COLOUR_PALETTE};
+    use crate::packed::PackedPlanes;
+
+    #[test]
+    fn test_palette_colours_stay_exact() {
+        let mut errors = [[0i16; 3]; 9];
+        let mut ditherer = RowDitherer::new(3, &TRI_COLOUR_PALETTE, Kernel::FloydSteinberg, &mut errors).unwrap();
+        let rgb = [0x00, 0x00, 0x00, 0x93, 0x78, 0x00, 0xff, 0xff, 0xff];
+        let mut out = [9u8; 3];
+        for _ in 0..4 {
+            ditherer.dither_row(&rgb, &mut out).unwrap();
+            assert_eq!(out, [0, 1, 2]);
+        }
+        assert_eq!(ditherer.row(), 4);
+    }
+
+    #[test]
+    fn test_grey_mixes_black_and_white() {
+        for &kernel in [Kernel::FloydSteinberg, Kernel::Atkinson].iter() {
+            let mut errors = [[0i16; 3]; 48];
+            let mut ditherer = RowDitherer::new(16, &BLACK_WHITE_PALETTE, kernel, &mut errors).unwrap();
+            let rgb = [0x80u8; 48];
+            let mut white = 0;
+            for _ in 0..16 {
+                ditherer.dither_row_with(&rgb, |_, val| if val == 2 { white += 1 }).unwrap();
+            }
+            assert!(white > 112 && white < 144, "{:?} gave {} white pixels", kernel, white);
+        }
+    }
+
+    #[test]
+    fn test_rows_into_planes() {
+        let mut errors = [[0i16; 3]; 30];
+        let mut ditherer = RowDitherer::new(10, &TRI_COLOUR_PALETTE, Kernel::Atkinson, &mut errors).unwrap();
+        let mut bw = [0u8; 4];
+        let mut accent = [0u8; 4];
+        let mut planes = PackedPlanes::new(10, 2, &mut bw, &mut accent).unwrap();
+        let black = [0u8; 30];
+        let white = [0xffu8; 30];
+        ditherer.dither_row_with(&black, |x, val| planes.set_pixel(x, 0, val)).unwrap();
+        ditherer.dither_row_with(&white, |x, val| planes.set_pixel(x, 1, val)).unwrap();
+
+        assert_eq!(planes.bw(), &[0x00, 0x00, 0xff, 0xc0]);
+        assert_eq!(planes.accent(), &[0x00; 4]);
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let mut errors = [[0i16; 3]; 10];
+        assert_eq!(error_buffer_len(4), 12);
+        assert!(RowDitherer::new(4, &BLACK_WHITE_PALETTE, Kernel::Atkinson, &mut errors).is_err());
+        assert!(RowDitherer::new(3, &[], Kernel::Atkinson, &mut errors).is_err());
+
+        let mut ditherer = RowDitherer::new(3, &BLACK_WHITE_PALETTE, Kernel::Atkinson, &mut errors).unwrap();
+        assert!(matches!(ditherer.dither_row(&[0; 8], &mut [0; 3]), Err(Error::BufferSizeError)));
+        assert!(matches!(ditherer.dither_row(&[0; 9], &mut [0; 2]), Err(Error::BufferSizeError)));
+    }
+}
@@ -1,43 +1,158 @@
 use crate::controller::il0371::*;
 
-use crate::controller::display_connector::{DisplayConnector, Result};
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
 
 use crate::controller::gd7965::GD7965;
-use crate::display::EPaperDisplay;
+use crate::controller::settings::{DataInterval, NonOverlap};
+use crate::controller::uc_family::{SelfTestReport, UcFamilyController};
+use crate::display::{Border, EPaperDisplay};
+use crate::packed::{nibble, nibble_line_bytes};
+
+/// Settings sent to the IL0371 by `init`, the defaults are the values
+/// from the vendor sample code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanelConfig {
+    /// power setting, defaults to internal source and gate power
+    pub power: PWRFlags,
+    /// defaults to 0x3c (50Hz)
+    pub frame_rate: FrameRate,
+    /// defaults to 0x1e
+    pub vcom: VcomLevel,
+    /// booster soft start phases A, B and C, defaults to [0xc7, 0xcc, 0x28]
+    pub booster_soft_start: [u8; 3],
+    /// defaults to white
+    pub border: Border,
+    /// defaults to 7 (10 frames)
+    pub data_interval: DataInterval,
+    /// defaults to 2 (12us)
+    pub source_to_gate: NonOverlap,
+    /// defaults to 2 (12us)
+    pub gate_to_source: NonOverlap,
+    /// value for the undocumented 0xe5 command, defaults to 3
+    pub flash_define: u8,
+}
+
+impl Default for PanelConfig {
+    fn default() -> Self {
+        PanelConfig {
+            power: PWRFlags::EDATA_SEL | PWRFlags::EDATA_SET | PWRFlags::VSOURCE_LV_EN | PWRFlags::VSOURCE_EN | PWRFlags::VGATE_EN,
+            frame_rate: FrameRate(0x3c),
+            vcom: VcomLevel(0x1e),
+            booster_soft_start: [0xc7, 0xcc, 0x28],
+            border: Border::White,
+            data_interval: DataInterval(7),
+            source_to_gate: NonOverlap(2),
+            gate_to_source: NonOverlap(2),
+            flash_define: 3,
+        }
+    }
+}
 
 pub struct EPaper75TriColour<T : DisplayConnector> {
     controller: IL0371<T>,
     pub width: u16,
     pub height: u16,
+    config: PanelConfig,
+    refreshing: bool,
+    // POF while already off isn't harmful but BUSY still pulses for it
+    powered: bool,
 }
 
 impl<T: DisplayConnector> EPaper75TriColour<T>  {
     pub fn new(connector : T) -> EPaper75TriColour<T> {
+        Self::with_config(connector, PanelConfig::default())
+    }
+
+    pub fn with_config(connector : T, config: PanelConfig) -> EPaper75TriColour<T> {
         let controller = IL0371::new(connector);
-        EPaper75TriColour { controller, width: 640, height: 384 }
+        EPaper75TriColour { controller, width: 640, height: 384, config, refreshing: false, powered: false }
+    }
+
+    pub fn config(&self) -> &PanelConfig {
+        &self.config
+    }
+
+    /// Resets the controller and checks it responds, without waiting
+    /// for a running refresh. Call `init` again before drawing.
+    pub fn self_test(&mut self) -> Result<SelfTestReport> {
+        self.refreshing = false;
+        self.powered = false;
+        self.controller.self_test()
+    }
+
+    /// Changes the border straight away, unlike `set_border` which waits
+    /// for the next `init`. Only the border bits are sent.
+    pub fn update_border(&mut self, border: Border) -> Result<()> {
+        self.wait()?;
+        self.config.border = border;
+        self.controller.update_border(Self::map_border_value(border))
+    }
+
+    /// Registers written since the last reset, for diagnostics.
+    pub fn registers(&self) -> &Registers {
+        self.controller.registers()
     }
 
     pub fn sleep(&mut self) -> Result<()> {
-        self.controller.pof_power_off()?;
+        self.wait()?;
+        self.power_off()?;
         self.controller.await_ready_state()?;
         self.controller.dslp_deep_sleep()
     }
 
     pub fn clear_with_val(&mut self, val: u8) -> Result<()>{
+        self.wait()?;
         let size: u32 = (self.width as u32 * self.height as u32) / 2;
         self.controller.transmit_with(size, |_| val)?;
-        self.controller.pon_power_on()?;
+        self.refresh()
+    }
+
+    /// Pushes an already packed 4bpp frame, see `packed::PackedNibbles`.
+    pub fn push_packed(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() != nibble_line_bytes(self.width) * self.height as usize {
+            return Err(Error::BufferSizeError);
+        }
+        self.wait()?;
+        self.controller.transmit(data)?;
+        self.refresh()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.trigger_refresh()?;
+        self.wait()
+    }
+
+    // power off happens in wait, once the refresh is done
+    fn trigger_refresh(&mut self) -> Result<()> {
+        self.power_on()?;
         self.controller.await_ready_state()?;
         self.controller.drf_display_refresh()?;
-        self.controller.await_ready_state()?;
-        self.controller.pof_power_off()
+        self.refreshing = true;
+        Ok(())
     }
 
-    fn map_pix_value(val: u8) -> u8 {
-        match val {
-            0 => 0x0,
-            1 => 0x4,
-            _ => 0x3
+    fn power_on(&mut self) -> Result<()> {
+        self.controller.pon_power_on()?;
+        self.powered = true;
+        Ok(())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        if !self.powered {
+            return Ok(());
+        }
+        self.controller.pof_power_off()?;
+        self.powered = false;
+        Ok(())
+    }
+
+    // the border takes a pixel value, same encoding as packed::nibble
+    fn map_border_value(border: Border) -> u8 {
+        match border {
+            Border::Black => 0x0,
+            Border::White => 0x3,
+            Border::Accent => 0x4,
+            Border::Floating => 0x7
         }
     }
 }
@@ -45,17 +160,22 @@ impl<T: DisplayConnector> EPaper75TriColour<T>  {
 impl<T : DisplayConnector> EPaperDisplay for EPaper75TriColour<T> {
 
     fn init(&mut self) -> Result<()> {
+        // whatever was running before is cut short by the reset
+        self.refreshing = false;
+        self.powered = false;
         self.controller.reset()?;
-        self.controller.pwr_power_setting(PWRFlags::EDATA_SEL | PWRFlags::EDATA_SET | PWRFlags::VSOURCE_LV_EN | PWRFlags::VSOURCE_EN | PWRFlags::VGATE_EN)?;
+        let config = self.config;
+        self.controller.pwr_power_setting(config.power)?;
         self.controller.psr_panel_setting(PSRFlags::RES_600_448 | PSRFlags::UD | PSRFlags::SHL | PSRFlags::SHD_N | PSRFlags::RST_N | PSRFlags::MYSTERY)?;
-        self.controller.pll_control(0x3c)?;
-        self.controller.vcom_dc_setting(0x1E)?;
-        self.controller.btst_booster_soft_start(0xc7, 0xcc, 0x28)?;
-        self.controller.cdi_vcom_and_data_interval_settings(3, true, 7)?;
-        self.controller.tcon_setting(0x22)?;
+        self.controller.pll_control(config.frame_rate)?;
+        self.controller.vcom_dc_setting(config.vcom)?;
+        let [pha, phb, phc] = config.booster_soft_start;
+        self.controller.btst_booster_soft_start(pha, phb, phc)?;
+        self.controller.cdi_vcom_and_data_interval_settings(Self::map_border_value(config.border), true, config.data_interval)?;
+        self.controller.tcon_setting(config.source_to_gate, config.gate_to_source)?;
         self.controller.dam_spi_flash_control(false)?;
         self.controller.tres_resolution(self.width, self.height)?;
-        self.controller.define_flash(3)
+        self.controller.define_flash(config.flash_define)
 
         // self.controller.pon_power_on()?;
         // self.controller.await_ready_state()?;
@@ -63,33 +183,36 @@ impl<T : DisplayConnector> EPaperDisplay for EPaper75TriColour<T> {
 
     }
 
+    fn set_border(&mut self, border: Border) {
+        self.config.border = border;
+    }
+
     fn clear(&mut self) -> Result<()>{
+        self.wait()?;
         let size: u32 = (self.width as u32 * self.height as u32) / 2;
         self.controller.transmit_with(size, |_| 0x00)?;
-        self.controller.pon_power_on()?;
+        self.power_on()?;
         self.controller.await_ready_state()?;
         self.controller.drf_display_refresh()?;
         self.controller.await_ready_state()
 //        self.controller.pof_power_off()
     }
 
-    fn push_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
-        let linebytes : u32 = (self.width / 2) as u32;
-        let size: u32 = (linebytes * self.height as u32);
+    fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.wait()?;
+        let linebytes = nibble_line_bytes(self.width) as u32;
+        let size: u32 = linebytes * self.height as u32;
+        let width = self.width as u32;
         self.controller.transmit_with(size, |offset| {
             let y = offset / linebytes;
             let x = (offset % linebytes) * 2;
-            let p1 = Self::map_pix_value(source(x, y)) << 4;
-            let p2 = Self::map_pix_value(source(x + 1, y));
+            let p1 = nibble(source(x, y)) << 4;
+            // padding at the end of a line is white
+            let p2 = nibble(if x + 1 < width { source(x + 1, y) } else { 2 });
             p1 | p2
         })?;
+        self.trigger_refresh()
 
-
-        self.controller.pon_power_on()?;
-        self.controller.await_ready_state()?;
-        self.controller.drf_display_refresh()?;
-        self.controller.await_ready_state()?;
-        self.controller.pof_power_off()
         // think this fixes high contrast situations like lines
         // not sure it works or really adds anythign
         //    self.controller.ipc_image_process(true,3);
@@ -101,6 +224,22 @@ impl<T : DisplayConnector> EPaperDisplay for EPaper75TriColour<T> {
         // self.controller.pof_power_off();
     }
 
+    fn is_refreshing(&mut self) -> Result<bool> {
+        if !self.refreshing {
+            return Ok(false);
+        }
+        self.controller.is_busy()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        if !self.refreshing {
+            return Ok(());
+        }
+        self.controller.await_ready_state()?;
+        self.refreshing = false;
+        self.power_off()
+    }
+
     fn width(&self) -> u16 {
         self.width
     }
@@ -108,4 +247,136 @@ impl<T : DisplayConnector> EPaperDisplay for EPaper75TriColour<T> {
     fn height(&self) -> u16 {
         self.height
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::controller::display_connector::Error;
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::display::EPaperDisplay;
+    use crate::epd7in5_tri_v1::EPaper75TriColour;
+    use crate::packed::{nibble_line_bytes, PackedNibbles};
+    use crate::test_kit::{expected_pixels, line_end_pattern, unpack_nibbles, MockPanel};
+
+    fn display() -> EPaper75TriColour<MockPanel> {
+        let mut display = EPaper75TriColour::new(MockPanel::with_busy_reads(2));
+        display.init().unwrap();
+        display.controller.connector().clear();
+        display
+    }
+
+    // a smaller panel, the frame is packed for whatever size the display has
+    fn shrunk(width: u16, height: u16) -> EPaper75TriColour<MockPanel> {
+        let mut display = display();
+        display.width = width;
+        display.height = height;
+        display
+    }
+
+    #[test]
+    fn test_init_sequence() {
+        let mut display = EPaper75TriColour::new(MockPanel::with_busy_reads(2));
+        display.init().unwrap();
+        assert_eq!(display.controller.connector().golden(), [
+            "reset 10",
+            "delay 10",
+            "wait",
+            "01 37 00",
+            "00 cf 08",
+            "30 3c",
+            "82 1e",
+            "06 c7 cc 28",
+            "50 77",
+            "60 22",
+            "65 00",
+            "61 02 80 01 80",
+            "e5 03",
+        ]);
+    }
+
+    #[test]
+    fn test_frame_sequences() {
+        let mut display = display();
+        display.push_image_with(|_, _| 2).unwrap();
+        assert_eq!(display.controller.connector().golden(), [
+            "10 [122880 x 33]",
+            "04",
+            "wait",
+            "12",
+            "wait",
+            "02",
+        ]);
+
+        display.controller.connector().clear();
+        display.start_image_with(|_, _| 1).unwrap();
+        assert!(display.is_refreshing().unwrap());
+        display.sleep().unwrap();
+        // wait has already powered off by the time sleep does
+        assert_eq!(display.controller.connector().golden(), [
+            "10 [122880 x 44]",
+            "04",
+            "wait",
+            "12",
+            "busy",
+            "wait",
+            "02",
+            "wait",
+            "07 a5",
+        ]);
+    }
+
+    #[test]
+    fn test_power_state() {
+        // clear leaves the panel powered, sleep has to power it off
+        let mut cleared = display();
+        cleared.clear().unwrap();
+        cleared.controller.connector().clear();
+        cleared.sleep().unwrap();
+        assert_eq!(cleared.controller.connector().golden(), ["02", "wait", "07 a5"]);
+
+        // init cuts a refresh short, there is nothing left to wait for
+        let mut interrupted = display();
+        interrupted.start_image_with(|_, _| 0).unwrap();
+        interrupted.init().unwrap();
+        assert!(!interrupted.is_refreshing().unwrap());
+        interrupted.controller.connector().clear();
+        interrupted.sleep().unwrap();
+        assert_eq!(interrupted.controller.connector().golden(), ["wait", "07 a5"]);
+    }
+
+    #[test]
+    fn test_clear_sequence() {
+        let mut display = display();
+        display.clear().unwrap();
+        assert_eq!(display.controller.connector().golden(), [
+            "10 [122880 x 00]",
+            "04",
+            "wait",
+            "12",
+            "wait",
+        ]);
+    }
+
+    #[test]
+    fn test_line_end_packing() {
+        for &width in [1u16, 2, 3, 8, 13].iter() {
+            let mut display = shrunk(width, 3);
+            display.push_image_with(line_end_pattern).unwrap();
+            let data = display.controller.connector().sent(0x10);
+            assert_eq!(unpack_nibbles(&data, width as u32, 3), expected_pixels(width as u32, 3, line_end_pattern), "width {}", width);
+
+            // packing up front gives the same frame
+            let mut packed = vec![0u8; nibble_line_bytes(width) * 3];
+            let mut nibbles = PackedNibbles::new(width, 3, &mut packed).unwrap();
+            nibbles.fill_with(line_end_pattern);
+            assert_eq!(nibbles.data(), &data[..], "width {}", width);
+
+            let mut display = shrunk(width, 3);
+            display.push_packed(&packed).unwrap();
+            assert_eq!(display.controller.connector().sent(0x10), data, "width {}", width);
+        }
+
+        let mut display = shrunk(13, 3);
+        assert!(matches!(display.push_packed(&[0; 20]), Err(Error::BufferSizeError)));
+        assert!(display.controller.connector().golden().is_empty());
+    }
+}
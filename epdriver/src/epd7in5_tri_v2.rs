@@ -1,118 +1,286 @@
-use crate::controller::il0371::*;
-
 use crate::controller::display_connector::{DisplayConnector, Result};
 
-use crate::controller::gd7965::{ GD7965, PWRFlags, PSRFlags };
-use crate::display::EPaperDisplay;
+use crate::controller::gd7965::Registers;
+use crate::controller::uc_family::SelfTestReport;
+use crate::display::{Border, EPaperDisplay, RefreshMode, Window};
+use crate::gd7965_panel::{GD7965Panel, PanelSpec};
 
+pub use crate::gd7965_panel::PanelConfig;
 
+/// The Waveshare 7.5" V2 tri-colour panel, a `GD7965Panel` with
+/// `PanelSpec::epd7in5_tri_v2()`.
 pub struct EPaper75TriColourV2<T : DisplayConnector> {
-    controller: GD7965<T>,
-    pub width: u16,
-    pub height: u16,
+    panel: GD7965Panel<T>,
 }
 
 impl<T : DisplayConnector> EPaper75TriColourV2<T> {
     pub fn new(connector : T) -> EPaper75TriColourV2<T> {
-        let controller = GD7965::new(connector);
-        EPaper75TriColourV2 { controller, width: 800, height: 480 }
+        Self::with_config(connector, PanelConfig::default())
+    }
+
+    pub fn with_config(connector : T, config: PanelConfig) -> EPaper75TriColourV2<T> {
+        EPaper75TriColourV2 { panel: GD7965Panel::with_valid_spec(connector, PanelSpec::epd7in5_tri_v2(), config) }
+    }
+
+    pub fn panel(&self) -> &GD7965Panel<T> {
+        &self.panel
+    }
+
+    pub fn config(&self) -> &PanelConfig {
+        self.panel.config()
+    }
+
+    pub fn refresh_mode(&self) -> RefreshMode {
+        self.panel.refresh_mode()
+    }
+
+    /// Switches between the tri-colour and the much faster black/white waveform.
+    /// Only the panel setting register is rewritten, so this is cheap to do
+    /// before every frame. Call after `init`.
+    pub fn set_refresh_mode(&mut self, mode: RefreshMode) -> Result<()> {
+        self.panel.set_refresh_mode(mode)
+    }
+
+    /// Resets the controller and checks it responds, without waiting
+    /// for a running refresh. Call `init` again before drawing.
+    pub fn self_test(&mut self) -> Result<SelfTestReport> {
+        self.panel.self_test()
+    }
+
+    /// Changes the border straight away, unlike `set_border` which waits
+    /// for the next `init`. Only the border bits are sent.
+    pub fn update_border(&mut self, border: Border) -> Result<()> {
+        self.panel.update_border(border)
+    }
+
+    /// Registers written since the last reset, for diagnostics.
+    pub fn registers(&self) -> &Registers {
+        self.panel.registers()
     }
 
     pub fn sleep(&mut self) -> Result<()> {
-        self.controller.pof_power_off()?;
-        self.controller.await_ready_state()?;
-        self.controller.dslp_deep_sleep()
+        self.panel.sleep()
     }
 
     pub fn clear_with_val(&mut self, val: u8) -> Result<()>{
-        let size: u32 = (self.width as u32 * self.height as u32) / 2;
-        self.controller.transmit_with(size, |_| val)?;
-        self.controller.pon_power_on()?;
-        self.controller.await_ready_state()?;
-        self.controller.drf_display_refresh()?;
-        self.controller.await_ready_state()?;
-        self.controller.pof_power_off()
+        self.panel.clear_with_val(val)
+    }
+
+    /// Pushes already packed black/white and accent planes, see `packed::PackedPlanes`.
+    pub fn push_planes(&mut self, bw: &[u8], accent: &[u8]) -> Result<()> {
+        self.panel.push_planes(bw, accent)
     }
 }
 
 impl<T : DisplayConnector> EPaperDisplay for EPaper75TriColourV2<T> {
     fn init(&mut self) -> Result<()> {
-        self.controller.reset()?;
-        self.controller.pwr_power_setting(PWRFlags::VSR_EN | PWRFlags::VS_EN | PWRFlags::VG_EN | PWRFlags::VG_LVL_20V, 15.0, -15.0, 3.0)?;
-        self.controller.pon_power_on()?;
-        self.controller.await_ready_state()?;
-        self.controller.psr_panel_setting(PSRFlags::UD | PSRFlags::SHL | PSRFlags::SHD_N | PSRFlags::RST_N )?;
-        self.controller.tres_resolution(800, 480)?;
-        self.controller.duspi_dual_spi_mode(false, false)?;
-        self.controller.cdi_vcom_and_data_interval_settings(false, 1, false, 1, 7)?;
-        self.controller.tcon_setting(2,2)?;
-        self.controller.gss_gate_source_start_setting(0,0)
+        self.panel.init()
+    }
+
+    fn set_border(&mut self, border: Border) {
+        self.panel.set_border(border)
     }
 
     fn clear(&mut self) -> Result<()>{
-        let size: u32 = (self.width as u32 * self.height as u32) / 2;
-        self.controller.transmit_with(size, |_| 0x00)?;
-        self.controller.pon_power_on()?;
-        self.controller.await_ready_state()?;
-        self.controller.drf_display_refresh()?;
-        self.controller.await_ready_state()?;
-        self.controller.pof_power_off()
-    }
-
-    fn push_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
-        let bytes_per_line = (self.width / 8) as u32;
-        let size: u32 = (self.width as u32 * self.height as u32);
-        self.controller.transmit_with(size, |offset| {
-            let y = offset / bytes_per_line;
-            let x = (offset % bytes_per_line) * 8;
-            let p7 = ((source(x, y) == 2) as u8) << 7;
-            let p6 = ((source(x+1, y) == 2) as u8) << 6;
-            let p5 = ((source(x+2, y) ==  2) as u8) << 5;
-            let p4 = ((source(x+3, y) == 2) as u8) << 4;
-            let p3 = ((source(x+4, y) ==  2) as u8) << 3;
-            let p2 = ((source(x+5, y) == 2) as u8) << 2;
-            let p1 = ((source(x+6, y) == 2) as u8)  <<  1;
-            let p0 = ((source(x+7, y) == 2) as u8);
-            p7 | p6 | p5 | p4 | p3 | p2 | p1 | p0
-        })?;
-        self.controller.await_ready_state()?;
-        self.controller.transmit_with2(size, |offset| {
-            let y = offset / bytes_per_line;
-            let x = (offset % bytes_per_line) * 8;
-            let p7 = ((source(x, y) == 1) as u8) << 7;
-            let p6 = ((source(x+1, y) == 1) as u8) << 6;
-            let p5 = ((source(x+2, y) == 1) as u8) << 5;
-            let p4 = ((source(x+3, y) == 1) as u8) << 4;
-            let p3 = ((source(x+4, y) == 1) as u8) << 3;
-            let p2 = ((source(x+5, y) == 1) as u8) << 2;
-            let p1 = ((source(x+6, y) == 1) as u8) << 1;
-            let p0 = (source(x+7, y) == 1) as u8;
-            p7 | p6 | p5 | p4 | p3 | p2 | p1 | p0
-        })?;
-
-
-
-        // self.controller.pon_power_on()?;
-        // self.controller.await_ready_state()?;
-         self.controller.drf_display_refresh();
-        self.controller.await_ready_state()
-
-        // think this fixes high contrast situations like lines
-        // not sure it works or really adds anythign
-        //    self.controller.ipc_image_process(true,3);
-        // self.controller.drf_display_refresh();
-        // self.controller.await_ready_state();
-        // self.controller.dam_spi_flash_control(true);
-        // self.controller.flash_data();
-        // self.controller.dam_spi_flash_control(false);
-        // self.controller.pof_power_off();
+        self.panel.clear_with_val(0x00)
+    }
+
+    fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.panel.start_image_with(source)
+    }
+
+    fn push_window_with<F>(&mut self, window: Window, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.panel.push_window_with(window, source)
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        self.panel.supports_partial_refresh()
+    }
+
+    fn is_refreshing(&mut self) -> Result<bool> {
+        self.panel.is_refreshing()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        self.panel.wait()
     }
 
     fn width(&self) -> u16 {
-        self.width
+        self.panel.width()
     }
 
     fn height(&self) -> u16 {
-        self.height
+        self.panel.height()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::display::{EPaperDisplay, RefreshMode, Window};
+    use crate::epd7in5_tri_v2::EPaper75TriColourV2;
+    use crate::packed::{plane_line_bytes, PackedPlanes};
+    use crate::test_kit::{expected_pixels, line_end_pattern, unpack_planes, MockPanel};
+
+    fn display() -> EPaper75TriColourV2<MockPanel> {
+        let mut display = EPaper75TriColourV2::new(MockPanel::with_busy_reads(2));
+        display.init().unwrap();
+        display.panel.controller.connector().clear();
+        display
+    }
+
+    // a smaller panel, the frame is packed for whatever size the display has
+    fn shrunk(width: u16, height: u16) -> EPaper75TriColourV2<MockPanel> {
+        let mut display = display();
+        display.panel.spec.width = width;
+        display.panel.spec.height = height;
+        display
+    }
+
+    #[test]
+    fn test_init_sequence() {
+        let mut display = EPaper75TriColourV2::new(MockPanel::with_busy_reads(2));
+        display.init().unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "reset 2",
+            "delay 10",
+            "wait",
+            "01 07 07 3f 3f 03",
+            "04",
+            "wait",
+            "00 0f",
+            "61 03 20 01 e0",
+            "15 00",
+            "50 11 07",
+            "60 22",
+            "65 00 00 00 00",
+        ]);
+    }
+
+    #[test]
+    fn test_frame_sequences() {
+        let mut display = display();
+        display.push_image_with(|_, _| 2).unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "10 [48000 x ff]",
+            "wait",
+            "13 [48000 x 00]",
+            "12",
+            "wait",
+        ]);
+
+        display.panel.controller.connector().clear();
+        display.set_refresh_mode(RefreshMode::Fast).unwrap();
+        display.start_image_with(|_, _| 0).unwrap();
+        assert!(display.is_refreshing().unwrap());
+        display.sleep().unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "00 1f",
+            "13 [48000 x 00]",
+            "12",
+            "busy",
+            "wait",
+            "02",
+            "wait",
+            "07 a5",
+        ]);
+    }
+
+    #[test]
+    fn test_clear_sequence() {
+        let mut display = display();
+        display.clear().unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "10 [48000 x 00]",
+            "04",
+            "wait",
+            "12",
+            "wait",
+            "02",
+        ]);
+
+        // clear powered off, the next frame has to power on again
+        display.panel.controller.connector().clear();
+        display.push_image_with(|_, _| 2).unwrap();
+        display.sleep().unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "10 [48000 x ff]",
+            "wait",
+            "13 [48000 x 00]",
+            "04",
+            "wait",
+            "12",
+            "wait",
+            "02",
+            "wait",
+            "07 a5",
+        ]);
+    }
+
+    #[test]
+    fn test_window_sequence() {
+        let mut display = display();
+        display.push_window_with(Window { x: 9, y: 1, width: 10, height: 2 }, |_, _| 1).unwrap();
+        assert_eq!(display.panel.controller.connector().golden(), [
+            "91",
+            "90 00 08 00 17 00 01 00 02 01",
+            "10 00 00 00 00",
+            "wait",
+            "13 ff ff ff ff",
+            "12",
+            "wait",
+            "92",
+        ]);
+    }
+
+    #[test]
+    fn test_line_end_packing() {
+        for &width in [1u16, 7, 8, 9, 13, 17].iter() {
+            let mut display = shrunk(width, 3);
+            display.push_image_with(line_end_pattern).unwrap();
+            let panel = display.panel.controller.connector();
+            let (bw, accent) = (panel.sent(0x10), panel.sent(0x13));
+            assert_eq!(unpack_planes(&bw, &accent, width as u32, 3), expected_pixels(width as u32, 3, line_end_pattern), "width {}", width);
+
+            // packing up front gives the same planes
+            let size = plane_line_bytes(width) * 3;
+            let (mut packed_bw, mut packed_accent) = (vec![0u8; size], vec![0u8; size]);
+            let mut planes = PackedPlanes::new(width, 3, &mut packed_bw, &mut packed_accent).unwrap();
+            planes.fill_with(line_end_pattern);
+            assert_eq!((planes.bw(), planes.accent()), (&bw[..], &accent[..]), "width {}", width);
+
+            let mut display = shrunk(width, 3);
+            display.push_planes(&packed_bw, &packed_accent).unwrap();
+            let panel = display.panel.controller.connector();
+            assert_eq!((panel.sent(0x10), panel.sent(0x13)), (bw, accent), "width {}", width);
+        }
+    }
+
+    #[test]
+    fn test_fast_mode_packing() {
+        let mut display = shrunk(13, 2);
+        display.set_refresh_mode(RefreshMode::Fast).unwrap();
+        display.push_image_with(line_end_pattern).unwrap();
+        let panel = display.panel.controller.connector();
+        assert!(panel.sent(0x10).is_empty());
+
+        // accent shows black
+        let no_accent = vec![0u8; 4];
+        let expected: Vec<u8> = expected_pixels(13, 2, line_end_pattern).iter().map(|&val| if val == 1 { 0 } else { val }).collect();
+        assert_eq!(unpack_planes(&panel.sent(0x13), &no_accent, 13, 2), expected);
+    }
+
+    #[test]
+    fn test_window_line_ends() {
+        // the window is widened to whole bytes and cut off at the panel edge
+        let mut display = shrunk(13, 4);
+        display.push_window_with(Window { x: 9, y: 1, width: 10, height: 2 }, line_end_pattern).unwrap();
+        let panel = display.panel.controller.connector();
+        assert_eq!(panel.sent(0x90), [0x00, 0x08, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x02, 0x01]);
+
+        let pixels = unpack_planes(&panel.sent(0x10), &panel.sent(0x13), 5, 2);
+        let expected = expected_pixels(5, 2, |x, y| line_end_pattern(x + 8, y + 1));
+        assert_eq!(pixels, expected);
     }
 }
@@ -0,0 +1,223 @@
+use crate::controller::display_connector::{Error, Result};
+use crate::display::{EPaperDisplay, Window};
+
+/// Frame buffer that remembers what was last sent to the panel and only
+/// refreshes the bounding box of the pixels that changed since.
+///
+/// Both buffers are supplied by the caller and hold one pixel value per byte,
+/// so they have to be at least `width * height` bytes long. Every
+/// `full_refresh_interval` partial updates a full refresh is forced to clear
+/// the ghosting partial updates leave behind, 0 never forces one.
+pub struct TrackingFrameBuffer<'a, D> where D: EPaperDisplay {
+    display: D,
+    frame: &'a mut [u8],
+    shown: &'a mut [u8],
+    full_refresh_interval: u32,
+    partial_updates: u32,
+    needs_full_refresh: bool,
+}
+
+impl<'a, D> TrackingFrameBuffer<'a, D> where D: EPaperDisplay {
+    pub fn new(display: D, frame: &'a mut [u8], shown: &'a mut [u8], full_refresh_interval: u32) -> Result<TrackingFrameBuffer<'a, D>> {
+        let size = display.width() as usize * display.height() as usize;
+        if frame.len() < size || shown.len() < size {
+            return Err(Error::BufferSizeError);
+        }
+        Ok(TrackingFrameBuffer {
+            display,
+            frame: &mut frame[..size],
+            shown: &mut shown[..size],
+            full_refresh_interval,
+            partial_updates: 0,
+            // nothing is known about the panel content yet
+            needs_full_refresh: true,
+        })
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, val: u8) {
+        if let Some(offset) = self.offset(x, y) {
+            self.frame[offset] = val;
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Option<u8> {
+        self.offset(x, y).map(|offset| self.frame[offset])
+    }
+
+    pub fn fill(&mut self, val: u8) {
+        self.frame.iter_mut().for_each(|p| *p = val);
+    }
+
+    /// Bounding box of the pixels that differ from what is on the panel.
+    pub fn dirty_window(&self) -> Option<Window> {
+        let width = self.display.width() as usize;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (offset, (new, old)) in self.frame.iter().zip(self.shown.iter()).enumerate() {
+            if new != old {
+                let (x, y) = (offset % width, offset / width);
+                bounds = Some(match bounds {
+                    None => (x, y, x, y),
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                });
+            }
+        }
+        bounds.map(|(x0, y0, x1, y1)| Window {
+            x: x0 as u16,
+            y: y0 as u16,
+            width: (x1 - x0 + 1) as u16,
+            height: (y1 - y0 + 1) as u16,
+        })
+    }
+
+    /// Makes the next `flush` refresh the whole panel.
+    pub fn force_full_refresh(&mut self) {
+        self.needs_full_refresh = true;
+    }
+
+    /// Sends the changes since the last flush to the panel.
+    pub fn flush(&mut self) -> Result<()> {
+        let width = self.display.width() as usize;
+        let frame = &*self.frame;
+        let source = |x: u32, y: u32| frame.get(y as usize * width + x as usize).copied().unwrap_or(0);
+
+        let interval_reached = self.full_refresh_interval > 0 && self.partial_updates >= self.full_refresh_interval;
+        if self.needs_full_refresh || interval_reached || !self.display.supports_partial_refresh() {
+            if !self.needs_full_refresh && self.dirty_window().is_none() {
+                return Ok(());
+            }
+            self.display.push_image_with(source)?;
+            self.partial_updates = 0;
+            self.needs_full_refresh = false;
+        } else {
+            match self.dirty_window() {
+                None => return Ok(()),
+                Some(window) => self.display.push_window_with(window, source)?,
+            }
+            self.partial_updates += 1;
+        }
+        self.shown.copy_from_slice(self.frame);
+        Ok(())
+    }
+
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    pub fn release(self) -> D {
+        self.display
+    }
+
+    fn offset(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.display.width() as u32 && y < self.display.height() as u32 {
+            Some(y as usize * self.display.width() as usize + x as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use crate::controller::display_connector::Result;
+    use crate::display::{Border, EPaperDisplay, Window};
+    use crate::framebuffer::TrackingFrameBuffer;
+
+    #[derive(Debug, PartialEq)]
+    enum Push {
+        Full,
+        Window(Window),
+    }
+
+    struct PushRecorder {
+        partial: bool,
+        pushes: Vec<Push>,
+    }
+
+    impl EPaperDisplay for PushRecorder {
+        fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_border(&mut self, _border: Border) {}
+
+        fn start_image_with<F>(&mut self, _source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+            self.pushes.push(Push::Full);
+            Ok(())
+        }
+
+        fn is_refreshing(&mut self) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn wait(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn push_window_with<F>(&mut self, window: Window, _source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+            self.pushes.push(Push::Window(window));
+            Ok(())
+        }
+
+        fn supports_partial_refresh(&self) -> bool {
+            self.partial
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn width(&self) -> u16 {
+            8
+        }
+
+        fn height(&self) -> u16 {
+            4
+        }
+    }
+
+    #[test]
+    fn test_partial_updates() {
+        let mut frame = [2u8; 32];
+        let mut shown = [2u8; 32];
+        let display = PushRecorder { partial: true, pushes: Vec::new() };
+        let mut fb = TrackingFrameBuffer::new(display, &mut frame, &mut shown, 2).unwrap();
+
+        fb.flush().unwrap();
+        fb.flush().unwrap();
+        fb.set_pixel(1, 2, 0);
+        fb.set_pixel(5, 1, 1);
+        assert_eq!(fb.dirty_window(), Some(Window { x: 1, y: 1, width: 5, height: 2 }));
+        fb.flush().unwrap();
+        fb.set_pixel(7, 3, 0);
+        fb.flush().unwrap();
+        fb.set_pixel(0, 0, 0);
+        fb.flush().unwrap();
+
+        assert_eq!(fb.release().pushes, [
+            Push::Full,
+            Push::Window(Window { x: 1, y: 1, width: 5, height: 2 }),
+            Push::Window(Window { x: 7, y: 3, width: 1, height: 1 }),
+            Push::Full,
+        ]);
+    }
+
+    #[test]
+    fn test_full_refresh_fallback() {
+        let mut frame = [2u8; 32];
+        let mut shown = [2u8; 32];
+        let display = PushRecorder { partial: false, pushes: Vec::new() };
+        let mut fb = TrackingFrameBuffer::new(display, &mut frame, &mut shown, 0).unwrap();
+
+        fb.flush().unwrap();
+        fb.set_pixel(3, 3, 1);
+        fb.flush().unwrap();
+        fb.flush().unwrap();
+
+        assert_eq!(fb.release().pushes, [Push::Full, Push::Full]);
+    }
+}
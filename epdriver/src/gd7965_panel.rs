@@ -0,0 +1,694 @@
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
+
+use crate::controller::gd7965::{GD7965, DataIntervalSetting, PSRFlags, Registers, PWRFlags, SourceVoltage, NegativeSourceVoltage, FrameRate, VcomLevel};
+use crate::controller::settings::{ConfigError, ConfigResult, DataInterval, NonOverlap};
+use crate::controller::uc_family::{SelfTestReport, UcFamilyController};
+use crate::display::{Border, EPaperDisplay, RefreshMode, Window};
+use crate::packed::{accent_bit, bw_bit, grey_high_bit, grey_low_bit, plane_byte, plane_line_bytes};
+
+// selects the bit of a pixel value that goes into a plane
+type PlaneBit = fn(u8) -> bool;
+
+/// Pigments of the glass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourMode {
+    /// black and white only (KW)
+    BlackWhite,
+    /// black, white and an accent colour (KWR)
+    TriColour,
+}
+
+/// Bits per pixel sent to the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    /// pixel values as for `EPaperDisplay::push_image_with`
+    One,
+    /// 4 grey levels, pixel values go from 0 (black) to 3 (white).
+    /// Only for black/white glass and needs the waveforms in `GreyLuts`.
+    Two,
+}
+
+/// Waveforms for 2bpp grey, loaded into the LUT registers by `init`.
+/// DTM1 holds the high and DTM2 the low bit of every pixel, the four
+/// combinations pick the WW, KW, WK and KK waveforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GreyLuts {
+    pub vcom: &'static [u8],
+    pub ww: &'static [u8],
+    pub kw: &'static [u8],
+    pub wk: &'static [u8],
+    pub kk: &'static [u8],
+    pub border: &'static [u8],
+}
+
+/// Geometry and pixel format of a GD7965 (UC8179) panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanelSpec {
+    /// multiple of 8, at most 800
+    pub width: u16,
+    /// at most 600
+    pub height: u16,
+    /// first source line (HST), multiple of 8
+    pub source_start: u16,
+    /// first gate line (VST)
+    pub gate_start: u16,
+    pub colour_mode: ColourMode,
+    pub bit_depth: BitDepth,
+    /// required for `BitDepth::Two`
+    pub grey_luts: Option<GreyLuts>,
+}
+
+impl PanelSpec {
+    /// Spec of the Waveshare 7.5" V2 tri-colour panel.
+    pub fn epd7in5_tri_v2() -> PanelSpec {
+        PanelSpec {
+            width: 800,
+            height: 480,
+            source_start: 0,
+            gate_start: 0,
+            colour_mode: ColourMode::TriColour,
+            bit_depth: BitDepth::One,
+            grey_luts: None,
+        }
+    }
+
+    pub fn validate(&self) -> ConfigResult<()> {
+        // the controller drives 800 sources and 600 gates
+        if self.width == 0 || self.width % 8 != 0 || self.height == 0
+            || self.source_start as u32 + self.width as u32 > 800 || self.gate_start as u32 + self.height as u32 > 600 {
+            return Err(ConfigError::ResolutionOutOfRange);
+        }
+        if self.source_start % 8 != 0 {
+            return Err(ConfigError::OffsetOutOfRange);
+        }
+        match (self.bit_depth, self.colour_mode, self.grey_luts) {
+            (BitDepth::One, _, _) => Ok(()),
+            (BitDepth::Two, ColourMode::BlackWhite, Some(_)) => Ok(()),
+            _ => Err(ConfigError::UnsupportedMode),
+        }
+    }
+}
+
+/// Settings sent to the GD7965 by `init`, the defaults are the values
+/// from the vendor sample code. Settings left as `None` are not sent
+/// and the controller uses its own reset defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanelConfig {
+    /// power setting, defaults to internal power with VGH/VGL at 20V
+    pub power: PWRFlags,
+    /// black/white source voltage, defaults to 15V
+    pub vdh: SourceVoltage,
+    /// black/white negative source voltage, defaults to -15V
+    pub vdl: NegativeSourceVoltage,
+    /// accent source voltage, defaults to 3V
+    pub vdhr: SourceVoltage,
+    /// booster soft start phases A, B and C, not sent by default
+    pub booster_soft_start: Option<[u8; 3]>,
+    /// not sent by default (controller default is 50Hz)
+    pub frame_rate: Option<FrameRate>,
+    /// not sent by default
+    pub vcom: Option<VcomLevel>,
+    /// defaults to white
+    pub border: Border,
+    /// defaults to 7 (10 frames)
+    pub data_interval: DataInterval,
+    /// defaults to 2 (12us)
+    pub source_to_gate: NonOverlap,
+    /// defaults to 2 (12us)
+    pub gate_to_source: NonOverlap,
+}
+
+impl Default for PanelConfig {
+    fn default() -> Self {
+        PanelConfig {
+            power: PWRFlags::VSR_EN | PWRFlags::VS_EN | PWRFlags::VG_EN | PWRFlags::VG_LVL_20V,
+            vdh: SourceVoltage(0x3f),
+            vdl: NegativeSourceVoltage(0x3f),
+            vdhr: SourceVoltage(0x03),
+            booster_soft_start: None,
+            frame_rate: None,
+            vcom: None,
+            border: Border::White,
+            data_interval: DataInterval(7),
+            source_to_gate: NonOverlap(2),
+            gate_to_source: NonOverlap(2),
+        }
+    }
+}
+
+/// Any panel driven by a GD7965, for glass that doesn't have its own type.
+pub struct GD7965Panel<T : DisplayConnector> {
+    pub(crate) controller: GD7965<T>,
+    pub(crate) spec: PanelSpec,
+    config: PanelConfig,
+    refresh_mode: RefreshMode,
+    refreshing: bool,
+    // DRF on an unpowered controller doesn't drive the glass
+    powered: bool,
+    // new to old copy, set by DifferentialPanel
+    n2ocp: bool,
+}
+
+impl<T : DisplayConnector> GD7965Panel<T> {
+    pub fn new(connector : T, spec: PanelSpec) -> ConfigResult<GD7965Panel<T>> {
+        Self::with_config(connector, spec, PanelConfig::default())
+    }
+
+    pub fn with_config(connector : T, spec: PanelSpec, config: PanelConfig) -> ConfigResult<GD7965Panel<T>> {
+        spec.validate()?;
+        Ok(Self::with_valid_spec(connector, spec, config))
+    }
+
+    // for specs known to be valid, like the ones of the panel types
+    pub(crate) fn with_valid_spec(connector : T, spec: PanelSpec, config: PanelConfig) -> GD7965Panel<T> {
+        let controller = GD7965::new(connector);
+        GD7965Panel { controller, spec, config, refresh_mode: RefreshMode::Colour, refreshing: false, powered: false, n2ocp: false }
+    }
+
+    pub fn spec(&self) -> &PanelSpec {
+        &self.spec
+    }
+
+    pub fn config(&self) -> &PanelConfig {
+        &self.config
+    }
+
+    pub fn refresh_mode(&self) -> RefreshMode {
+        self.refresh_mode
+    }
+
+    /// Switches tri-colour glass between the colour and the black/white
+    /// waveform, black/white glass always uses the latter. Call after `init`.
+    pub fn set_refresh_mode(&mut self, mode: RefreshMode) -> Result<()> {
+        self.wait()?;
+        self.refresh_mode = mode;
+        self.controller.update_psr(self.psr_flags())
+    }
+
+    /// Resets the controller and checks it responds, without waiting
+    /// for a running refresh. Call `init` again before drawing.
+    pub fn self_test(&mut self) -> Result<SelfTestReport> {
+        self.refreshing = false;
+        self.powered = false;
+        self.controller.self_test()
+    }
+
+    /// Changes the border straight away, unlike `set_border` which waits
+    /// for the next `init`. Only the border bits are sent.
+    pub fn update_border(&mut self, border: Border) -> Result<()> {
+        self.wait()?;
+        self.config.border = border;
+        let (bdz, bdv) = Self::map_border_value(border);
+        self.controller.update_border(bdz, bdv)
+    }
+
+    /// Registers written since the last reset, for diagnostics.
+    pub fn registers(&self) -> &Registers {
+        self.controller.registers()
+    }
+
+    pub fn sleep(&mut self) -> Result<()> {
+        self.wait()?;
+        self.power_off()?;
+        self.controller.await_ready_state()?;
+        self.controller.dslp_deep_sleep()
+    }
+
+    /// Fills the old data register (DTM1) with `val` and refreshes, the way
+    /// the vendor sample code clears the V2 panel.
+    pub fn clear_with_val(&mut self, val: u8) -> Result<()> {
+        self.wait()?;
+        let size = plane_line_bytes(self.spec.width) as u32 * self.spec.height as u32;
+        self.controller.transmit_with(size, |_| val)?;
+        self.power_on()?;
+        self.controller.await_ready_state()?;
+        self.controller.drf_display_refresh()?;
+        self.controller.await_ready_state()?;
+        self.power_off()
+    }
+
+    /// Pushes already packed planes, see `packed::PackedPlanes`. With the
+    /// black/white waveform `second` is ignored for 1bpp and holds the low
+    /// grey bit for 2bpp.
+    pub fn push_planes(&mut self, first: &[u8], second: &[u8]) -> Result<()> {
+        let size = plane_line_bytes(self.spec.width) * self.spec.height as usize;
+        if first.len() != size || (self.uses_both_planes() && second.len() != size) {
+            return Err(Error::BufferSizeError);
+        }
+        self.wait()?;
+        if self.uses_both_planes() {
+            self.controller.transmit(first)?;
+            self.controller.await_ready_state()?;
+            self.controller.transmit2(second)?;
+        } else {
+            self.controller.transmit2(first)?;
+        }
+        self.refresh()
+    }
+
+    fn kw_mode(&self) -> bool {
+        self.spec.colour_mode == ColourMode::BlackWhite || self.refresh_mode == RefreshMode::Fast
+    }
+
+    fn uses_both_planes(&self) -> bool {
+        !self.kw_mode() || self.spec.bit_depth == BitDepth::Two
+    }
+
+    // pixel value of a white pixel
+    fn white(&self) -> u8 {
+        match self.spec.bit_depth {
+            BitDepth::One => 2,
+            BitDepth::Two => 3,
+        }
+    }
+
+    // bits sent to DTM1 and DTM2, in black/white 1bpp only DTM2 is used
+    fn plane_bits(&self) -> (Option<PlaneBit>, PlaneBit) {
+        match (self.kw_mode(), self.spec.bit_depth) {
+            (_, BitDepth::Two) => (Some(grey_high_bit), grey_low_bit),
+            (false, BitDepth::One) => (Some(bw_bit), accent_bit),
+            (true, BitDepth::One) => (None, bw_bit),
+        }
+    }
+
+    fn transmit_frame_with<F>(&mut self, size: u32, pack: F) -> Result<()> where F: Fn(u32, PlaneBit) -> u8 {
+        let (first, second) = self.plane_bits();
+        if let Some(first) = first {
+            self.controller.transmit_with(size, |offset| pack(offset, first))?;
+            self.controller.await_ready_state()?;
+        }
+        self.controller.transmit_with2(size, |offset| pack(offset, second))
+    }
+
+    fn psr_flags(&self) -> PSRFlags {
+        let flags = PSRFlags::UD | PSRFlags::SHL | PSRFlags::SHD_N | PSRFlags::RST_N;
+        match (self.kw_mode(), self.spec.bit_depth) {
+            (_, BitDepth::Two) => flags | PSRFlags::KW_R | PSRFlags::REG,
+            (true, BitDepth::One) => flags | PSRFlags::KW_R,
+            (false, BitDepth::One) => flags,
+        }
+    }
+
+    fn load_grey_luts(&mut self) -> Result<()> {
+        if let Some(luts) = self.spec.grey_luts.filter(|_| self.spec.bit_depth == BitDepth::Two) {
+            self.controller.lutc_vcom_lut(luts.vcom)?;
+            self.controller.lutww_white_to_white_lut(luts.ww)?;
+            self.controller.lutkw_black_to_white_lut(luts.kw)?;
+            self.controller.lutwk_white_to_black_lut(luts.wk)?;
+            self.controller.lutkk_black_to_black_lut(luts.kk)?;
+            self.controller.lutbd_border_lut(luts.border)?;
+        }
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.trigger_refresh()?;
+        self.wait()
+    }
+
+    // the controller stays powered between frames, only a clear turns it off
+    fn trigger_refresh(&mut self) -> Result<()> {
+        if !self.powered {
+            self.power_on()?;
+            self.controller.await_ready_state()?;
+        }
+        self.controller.drf_display_refresh()?;
+        self.refreshing = true;
+        Ok(())
+    }
+
+    // written straight away once CDI is known, init writes it otherwise
+    fn set_new_to_old_copy(&mut self, n2ocp: bool) -> Result<()> {
+        self.n2ocp = n2ocp;
+        match self.controller.registers().cdi {
+            Some(cdi) => {
+                self.wait()?;
+                self.controller.update_cdi(DataIntervalSetting { n2ocp, ..cdi })
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn power_on(&mut self) -> Result<()> {
+        self.controller.pon_power_on()?;
+        self.powered = true;
+        Ok(())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        if !self.powered {
+            return Ok(());
+        }
+        self.controller.pof_power_off()?;
+        self.powered = false;
+        Ok(())
+    }
+
+    // (bdz, bdv) with DDX=01, BDV selects LUTK/LUTW/LUTR
+    fn map_border_value(border: Border) -> (bool, u8) {
+        match border {
+            Border::Black => (false, 0),
+            Border::White => (false, 1),
+            Border::Accent => (false, 2),
+            Border::Floating => (true, 0)
+        }
+    }
+}
+
+impl<T : DisplayConnector> EPaperDisplay for GD7965Panel<T> {
+    fn init(&mut self) -> Result<()> {
+        self.powered = false;
+        self.controller.reset()?;
+        let config = self.config;
+        self.controller.pwr_power_setting(config.power, config.vdh, config.vdl, config.vdhr)?;
+        if let Some([pha, phb, phc]) = config.booster_soft_start {
+            self.controller.btst_booster_soft_start(pha, phb, phc, false, 0)?;
+        }
+        self.power_on()?;
+        self.controller.await_ready_state()?;
+        self.controller.psr_panel_setting(self.psr_flags())?;
+        self.load_grey_luts()?;
+        if let Some(frame_rate) = config.frame_rate {
+            self.controller.pll_control(frame_rate)?;
+        }
+        self.controller.tres_resolution(self.spec.width, self.spec.height)?;
+        self.controller.duspi_dual_spi_mode(false, false)?;
+        if let Some(vcom) = config.vcom {
+            self.controller.vcom_dc_setting(vcom)?;
+        }
+        let (bdz, bdv) = Self::map_border_value(config.border);
+        self.controller.cdi_vcom_and_data_interval_settings(bdz, bdv, self.n2ocp, 1, config.data_interval)?;
+        self.controller.tcon_setting(config.source_to_gate, config.gate_to_source)?;
+        self.controller.gss_gate_source_start_setting(self.spec.source_start, self.spec.gate_start)
+    }
+
+    fn set_border(&mut self, border: Border) {
+        self.config.border = border;
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        let white = self.white();
+        self.push_image_with(|_, _| white)
+    }
+
+    fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.wait()?;
+        let bytes_per_line = plane_line_bytes(self.spec.width) as u32;
+        let size: u32 = bytes_per_line * self.spec.height as u32;
+        let width = self.spec.width as u32;
+        let pack = |offset: u32, bit: PlaneBit| {
+            let y = offset / bytes_per_line;
+            let x = (offset % bytes_per_line) * 8;
+            plane_byte(&source, x, y, width, bit)
+        };
+        self.transmit_frame_with(size, pack)?;
+        self.trigger_refresh()
+    }
+
+    fn push_window_with<F>(&mut self, window: Window, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        let (width, height) = (self.spec.width, self.spec.height);
+        // the controller addresses whole bytes horizontally
+        let x_start = (window.x.min(width) & !7) as u32;
+        let x_end = ((window.x as u32 + window.width as u32).min(width as u32) + 7) & !7;
+        let y_start = window.y.min(height) as u32;
+        let y_end = (window.y as u32 + window.height as u32).min(height as u32);
+        if x_start >= x_end || y_start >= y_end {
+            return Ok(());
+        }
+        self.wait()?;
+
+        let bytes_per_line = (x_end - x_start) / 8;
+        let size = bytes_per_line * (y_end - y_start);
+        let pack = |offset: u32, bit: PlaneBit| {
+            let y = y_start + offset / bytes_per_line;
+            let x = x_start + (offset % bytes_per_line) * 8;
+            plane_byte(&source, x, y, width as u32, bit)
+        };
+        self.controller.ptin_partial_in()?;
+        self.controller.ptl_partial_window(x_start as u16, x_end as u16 - 1, y_start as u16, y_end as u16 - 1, true)?;
+        self.transmit_frame_with(size, pack)?;
+        self.refresh()?;
+        self.controller.ptout_partial_out()
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        true
+    }
+
+    fn is_refreshing(&mut self) -> Result<bool> {
+        if !self.refreshing {
+            return Ok(false);
+        }
+        self.controller.is_busy()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        if !self.refreshing {
+            return Ok(());
+        }
+        self.controller.await_ready_state()?;
+        self.refreshing = false;
+        Ok(())
+    }
+
+    fn width(&self) -> u16 {
+        self.spec.width
+    }
+
+    fn height(&self) -> u16 {
+        self.spec.height
+    }
+}
+
+/// Black/white panel that only drives the pixels that changed.
+///
+/// The previous frame is kept packed in a caller supplied buffer of at least
+/// `plane_line_bytes(width) * height` bytes and sent as old data (DTM1) next
+/// to the new frame (DTM2), so the controller picks the waveform per pixel
+/// from the old and new value. The panel is assumed to be white to start
+/// with, `clear` makes sure of that.
+pub struct DifferentialPanel<'a, T : DisplayConnector> {
+    panel: GD7965Panel<T>,
+    previous: &'a mut [u8],
+}
+
+impl<'a, T : DisplayConnector> DifferentialPanel<'a, T> {
+    /// Only black/white glass at 1bpp can refresh differentially. The new to
+    /// old copy this needs is turned on straight away for a panel that has
+    /// been initialised, otherwise by `init`.
+    pub fn new(mut panel: GD7965Panel<T>, previous: &'a mut [u8]) -> Result<DifferentialPanel<'a, T>> {
+        if panel.spec.colour_mode != ColourMode::BlackWhite || panel.spec.bit_depth != BitDepth::One {
+            return Err(ConfigError::UnsupportedMode.into());
+        }
+        let size = plane_line_bytes(panel.spec.width) * panel.spec.height as usize;
+        if previous.len() < size {
+            return Err(Error::BufferSizeError);
+        }
+        let previous = &mut previous[..size];
+        previous.iter_mut().for_each(|b| *b = 0xff);
+        panel.set_new_to_old_copy(true)?;
+        Ok(DifferentialPanel { panel, previous })
+    }
+
+    pub fn panel(&self) -> &GD7965Panel<T> {
+        &self.panel
+    }
+
+    pub fn self_test(&mut self) -> Result<SelfTestReport> {
+        self.panel.self_test()
+    }
+
+    pub fn sleep(&mut self) -> Result<()> {
+        self.panel.sleep()
+    }
+
+    /// Turns the new to old copy off again and hands the panel back.
+    pub fn release(mut self) -> Result<GD7965Panel<T>> {
+        self.panel.set_new_to_old_copy(false)?;
+        Ok(self.panel)
+    }
+}
+
+impl<T : DisplayConnector> EPaperDisplay for DifferentialPanel<'_, T> {
+    fn init(&mut self) -> Result<()> {
+        self.panel.init()
+    }
+
+    fn set_border(&mut self, border: Border) {
+        self.panel.set_border(border)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.push_image_with(|_, _| 2)
+    }
+
+    fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+        self.panel.wait()?;
+        self.panel.controller.transmit(self.previous)?;
+        self.panel.controller.await_ready_state()?;
+        let bytes_per_line = plane_line_bytes(self.panel.spec.width) as u32;
+        let width = self.panel.spec.width as u32;
+        let pack = |offset: u32| {
+            let y = offset / bytes_per_line;
+            let x = (offset % bytes_per_line) * 8;
+            plane_byte(&source, x, y, width, bw_bit)
+        };
+        self.panel.controller.transmit_with2(self.previous.len() as u32, pack)?;
+        self.panel.trigger_refresh()?;
+        // a failed transfer leaves the glass showing the old frame, so it
+        // only becomes the previous one once the refresh is under way
+        for (offset, byte) in self.previous.iter_mut().enumerate() {
+            *byte = pack(offset as u32);
+        }
+        Ok(())
+    }
+
+    fn is_refreshing(&mut self) -> Result<bool> {
+        self.panel.is_refreshing()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        self.panel.wait()
+    }
+
+    fn width(&self) -> u16 {
+        self.panel.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.panel.height()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::settings::ConfigError;
+    use crate::controller::uc_family::UcFamilyController;
+    use crate::display::EPaperDisplay;
+    use crate::gd7965_panel::{BitDepth, ColourMode, DifferentialPanel, GD7965Panel, GreyLuts, PanelSpec};
+    use crate::test_kit::MockPanel;
+
+    const LUT: [u8; 2] = [0xaa, 0x55];
+
+    fn spec(colour_mode: ColourMode, bit_depth: BitDepth) -> PanelSpec {
+        PanelSpec {
+            width: 16,
+            height: 2,
+            source_start: 8,
+            gate_start: 3,
+            colour_mode,
+            bit_depth,
+            grey_luts: Some(GreyLuts { vcom: &LUT, ww: &LUT, kw: &LUT, wk: &LUT, kk: &LUT, border: &LUT }),
+        }
+    }
+
+    #[test]
+    fn test_spec_validation() {
+        assert!(PanelSpec::epd7in5_tri_v2().validate().is_ok());
+        assert_eq!(PanelSpec { width: 12, ..spec(ColourMode::BlackWhite, BitDepth::One) }.validate(), Err(ConfigError::ResolutionOutOfRange));
+        assert_eq!(PanelSpec { source_start: 792, ..spec(ColourMode::BlackWhite, BitDepth::One) }.validate(), Err(ConfigError::ResolutionOutOfRange));
+        assert_eq!(PanelSpec { gate_start: 599, ..spec(ColourMode::BlackWhite, BitDepth::One) }.validate(), Err(ConfigError::ResolutionOutOfRange));
+        assert_eq!(PanelSpec { source_start: 4, ..spec(ColourMode::BlackWhite, BitDepth::One) }.validate(), Err(ConfigError::OffsetOutOfRange));
+        assert_eq!(spec(ColourMode::TriColour, BitDepth::Two).validate(), Err(ConfigError::UnsupportedMode));
+        assert_eq!(PanelSpec { grey_luts: None, ..spec(ColourMode::BlackWhite, BitDepth::Two) }.validate(), Err(ConfigError::UnsupportedMode));
+    }
+
+    #[test]
+    fn test_init_geometry() {
+        let mut panel = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::One)).unwrap();
+        panel.init().unwrap();
+        let recorder = panel.controller.connector();
+        assert_eq!(recorder.sent(0x61), [0x00, 0x10, 0x00, 0x02]);
+        assert_eq!(recorder.sent(0x65), [0x00, 0x08, 0x00, 0x03]);
+        // KW_R set, no LUTs from registers
+        assert_eq!(recorder.sent(0x00), [0x1f]);
+        assert!(recorder.sent(0x20).is_empty());
+    }
+
+    #[test]
+    fn test_planes() {
+        // black, accent, white, ... on the first line, white elsewhere
+        let source = |x: u32, y: u32| if y == 0 { x as u8 % 4 } else { 3 };
+
+        let mut tri = GD7965Panel::new(MockPanel::default(), spec(ColourMode::TriColour, BitDepth::One)).unwrap();
+        tri.push_image_with(source).unwrap();
+        let recorder = tri.controller.connector();
+        assert_eq!(recorder.sent(0x10), [0x33, 0x33, 0xff, 0xff]);
+        assert_eq!(recorder.sent(0x13), [0x44, 0x44, 0x00, 0x00]);
+
+        let mut bw = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::One)).unwrap();
+        bw.push_image_with(source).unwrap();
+        let recorder = bw.controller.connector();
+        assert!(recorder.sent(0x10).is_empty());
+        assert_eq!(recorder.sent(0x13), [0x33, 0x33, 0xff, 0xff]);
+
+        let mut grey = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::Two)).unwrap();
+        grey.init().unwrap();
+        grey.push_image_with(source).unwrap();
+        let recorder = grey.controller.connector();
+        assert_eq!(recorder.sent(0x00), [0x3f]);
+        assert_eq!(recorder.sent(0x20), LUT);
+        assert_eq!(recorder.sent(0x10), [0x33, 0x33, 0xff, 0xff]);
+        assert_eq!(recorder.sent(0x13), [0x55, 0x55, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_differential_refresh() {
+        let panel = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::One)).unwrap();
+        let mut previous = [0u8; 4];
+        let mut diff = DifferentialPanel::new(panel, &mut previous).unwrap();
+        diff.init().unwrap();
+        diff.push_image_with(|x, _| if x < 8 { 0 } else { 2 }).unwrap();
+        diff.push_image_with(|_, y| if y == 0 { 0 } else { 2 }).unwrap();
+
+        let mut panel = diff.release().unwrap();
+        let recorder = panel.controller.connector();
+        // N2OCP set in the VCOM and data interval setting by init, cleared by release
+        assert_eq!(recorder.sent(0x50), [0x19, 0x07, 0x11, 0x07]);
+        assert_eq!(recorder.sent(0x10), [0xff, 0xff, 0xff, 0xff, 0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(recorder.sent(0x13), [0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(previous, [0x00, 0x00, 0xff, 0xff]);
+
+        let tri = GD7965Panel::new(MockPanel::default(), spec(ColourMode::TriColour, BitDepth::One)).unwrap();
+        assert!(DifferentialPanel::new(tri, &mut [0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn test_differential_failure() {
+        let panel = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::One)).unwrap();
+        let mut previous = [0u8; 4];
+        let mut diff = DifferentialPanel::new(panel, &mut previous).unwrap();
+        diff.init().unwrap();
+        diff.panel.controller.connector().fail_on(Some(0x13));
+        assert!(diff.push_image_with(|_, _| 0).is_err());
+
+        // the failed frame never made it, the old data is still all white
+        diff.panel.controller.connector().fail_on(None);
+        diff.panel.controller.connector().clear();
+        diff.push_image_with(|x, _| if x < 8 { 0 } else { 2 }).unwrap();
+        assert_eq!(diff.panel.controller.connector().sent(0x10), [0xff, 0xff, 0xff, 0xff]);
+        diff.release().unwrap();
+        assert_eq!(previous, [0x00, 0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_differential_wraps_initialised_panel() {
+        let mut panel = GD7965Panel::new(MockPanel::default(), spec(ColourMode::BlackWhite, BitDepth::One)).unwrap();
+        panel.init().unwrap();
+        assert_eq!(panel.controller.connector().sent(0x50), [0x11, 0x07]);
+
+        // N2OCP goes on when wrapped, not only at the next init
+        panel.controller.connector().clear();
+        let mut previous = [0u8; 4];
+        let mut diff = DifferentialPanel::new(panel, &mut previous).unwrap();
+        assert_eq!(diff.panel.controller.connector().sent(0x50), [0x19, 0x07]);
+
+        diff.panel.controller.connector().clear();
+        let mut panel = diff.release().unwrap();
+        assert_eq!(panel.controller.connector().sent(0x50), [0x11, 0x07]);
+
+        panel.controller.connector().clear();
+        panel.init().unwrap();
+        assert_eq!(panel.controller.connector().sent(0x50), [0x11, 0x07]);
+    }
+}
@@ -3,19 +3,39 @@
 extern crate bitflags;
 
 mod controller;
-mod epd7in5_tri_v1;
-mod epd7in5_tri_v2;
+pub mod epd7in5_tri_v1;
+pub mod epd7in5_tri_v2;
+pub mod gd7965_panel;
 mod display;
+pub mod packed;
+pub mod framebuffer;
+pub mod recovery;
+pub mod dither;
+#[cfg(feature = "linux")]
+pub mod linux;
+#[cfg(test)]
+mod test_kit;
 
 pub use epd7in5_tri_v1::EPaper75TriColour;
 pub use epd7in5_tri_v2::EPaper75TriColourV2;
+pub use gd7965_panel::{DifferentialPanel, GD7965Panel};
 pub use display::EPaperDisplay;
 pub use display::DisplayError;
+pub use display::Border;
+pub use display::Window;
+pub use display::RefreshMode;
+pub use controller::bit_bang;
 pub use controller::display_connector;
+pub use controller::record;
+#[cfg(feature = "std")]
+pub use controller::remote;
 pub use controller::gd7965;
 pub use controller::il0371;
+pub use controller::settings;
+pub use controller::uc_family;
+pub use controller::uc_family::{SelfTestReport, UcFamilyController};
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 // define empty dbg macro
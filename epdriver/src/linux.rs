@@ -0,0 +1,262 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use embedded_hal::digital::v2::OutputPin;
+use linux_embedded_hal::{CdevPin, Delay, Spidev};
+use linux_embedded_hal::gpio_cdev::{self, Chip, LineHandle, LineRequestFlags};
+use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+
+use crate::controller::display_connector::{Error, SpiConnector};
+use crate::controller::settings::ConfigError;
+
+/// Where the spidev module exposes the largest transfer it accepts.
+pub const SPIDEV_BUFSIZ_PATH: &str = "/sys/module/spidev/parameters/bufsiz";
+
+// the spidev default, used when the parameter can't be read
+const DEFAULT_BUFSIZ: usize = 4096;
+
+/// Connector `LinuxConnectorBuilder` builds.
+pub type LinuxConnector = SpiConnector<Spidev, CdevPin, CdevPin, Delay, LinuxChipSelect>;
+
+#[derive(Debug)]
+pub enum LinuxError {
+    /// opening or configuring the spidev device failed
+    Spi(io::Error),
+    /// opening the gpiochip or requesting a line failed
+    Gpio(gpio_cdev::Error),
+    Connector(Error),
+}
+
+impl fmt::Display for LinuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinuxError::Spi(e) => write!(f, "spi: {}", e),
+            LinuxError::Gpio(e) => write!(f, "gpio: {}", e),
+            LinuxError::Connector(e) => write!(f, "connector: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for LinuxError {}
+
+impl From<io::Error> for LinuxError {
+    fn from(e: io::Error) -> Self {
+        LinuxError::Spi(e)
+    }
+}
+
+impl From<gpio_cdev::Error> for LinuxError {
+    fn from(e: gpio_cdev::Error) -> Self {
+        LinuxError::Gpio(e)
+    }
+}
+
+impl From<Error> for LinuxError {
+    fn from(e: Error) -> Self {
+        LinuxError::Connector(e)
+    }
+}
+
+/// Chip select line, `None` leaves CS to the spidev device.
+pub struct LinuxChipSelect(Option<CdevPin>);
+
+impl OutputPin for LinuxChipSelect {
+    type Error = gpio_cdev::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.as_mut().map_or(Ok(()), |pin| pin.set_low())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.as_mut().map_or(Ok(()), |pin| pin.set_high())
+    }
+}
+
+/// Opens a panel connected through spidev and the GPIO character device.
+///
+/// Line numbers are offsets on `gpiochip`, on a Raspberry Pi these are the
+/// BCM numbers. Without a CS line the spidev device drives its own chip
+/// select, with one it is told not to, so several panels can share a bus.
+///
+/// ```ignore
+/// let connector = LinuxConnectorBuilder::waveshare_hat().speed_hz(2_000_000).build()?;
+/// let display = EPaper75TriColourV2::new(connector);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LinuxConnectorBuilder {
+    spidev: PathBuf,
+    speed_hz: u32,
+    mode: SpiModeFlags,
+    gpiochip: PathBuf,
+    rst: u32,
+    dc: u32,
+    busy: u32,
+    cs: Option<u32>,
+    chunk_size: Option<usize>,
+}
+
+impl LinuxConnectorBuilder {
+    /// Same as `waveshare_hat`.
+    pub fn new() -> LinuxConnectorBuilder {
+        Self::waveshare_hat()
+    }
+
+    /// Waveshare e-Paper HAT and e-Paper Driver HAT on a Raspberry Pi, CS on CE0.
+    pub fn waveshare_hat() -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder {
+            spidev: PathBuf::from("/dev/spidev0.0"),
+            speed_hz: 4_000_000,
+            mode: SpiModeFlags::SPI_MODE_0,
+            gpiochip: PathBuf::from("/dev/gpiochip0"),
+            rst: 17,
+            dc: 25,
+            busy: 24,
+            cs: None,
+            chunk_size: None,
+        }
+    }
+
+    /// Pimoroni Inky boards (pHAT, wHAT, Impression) on a Raspberry Pi, CS on CE0.
+    pub fn pimoroni_inky() -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder {
+            rst: 27,
+            dc: 22,
+            busy: 17,
+            ..Self::waveshare_hat()
+        }
+    }
+
+    pub fn spidev<P>(self, path: P) -> LinuxConnectorBuilder where P: AsRef<Path> {
+        LinuxConnectorBuilder { spidev: path.as_ref().to_path_buf(), ..self }
+    }
+
+    pub fn speed_hz(self, speed_hz: u32) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { speed_hz, ..self }
+    }
+
+    pub fn mode(self, mode: SpiModeFlags) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { mode, ..self }
+    }
+
+    pub fn gpiochip<P>(self, path: P) -> LinuxConnectorBuilder where P: AsRef<Path> {
+        LinuxConnectorBuilder { gpiochip: path.as_ref().to_path_buf(), ..self }
+    }
+
+    pub fn rst(self, line: u32) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { rst: line, ..self }
+    }
+
+    pub fn dc(self, line: u32) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { dc: line, ..self }
+    }
+
+    pub fn busy(self, line: u32) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { busy: line, ..self }
+    }
+
+    /// Drives CS from a GPIO line, `None` leaves it to spidev.
+    pub fn cs(self, line: Option<u32>) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { cs: line, ..self }
+    }
+
+    /// Largest single transfer, `None` uses the spidev limit. spidev fails
+    /// writes longer than its limit, an override isn't checked against it.
+    pub fn chunk_size(self, chunk_size: Option<usize>) -> LinuxConnectorBuilder {
+        LinuxConnectorBuilder { chunk_size, ..self }
+    }
+
+    /// The chunk size `build` will use.
+    pub fn effective_chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or_else(|| spidev_bufsiz().unwrap_or(DEFAULT_BUFSIZ))
+    }
+
+    pub fn build(&self) -> Result<LinuxConnector, LinuxError> {
+        let chunk_size = self.effective_chunk_size();
+        if chunk_size == 0 {
+            return Err(Error::from(ConfigError::ValueOutOfRange).into());
+        }
+        let mut spi = Spidev::open(&self.spidev)?;
+        let mode = if self.cs.is_some() { self.mode | SpiModeFlags::SPI_NO_CS } else { self.mode };
+        spi.configure(&SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(self.speed_hz)
+            .mode(mode)
+            .build())?;
+
+        let mut chip = Chip::new(&self.gpiochip)?;
+        let rst = CdevPin::new(request(&mut chip, self.rst, LineRequestFlags::OUTPUT, 1, "epd rst")?)?;
+        let dc = CdevPin::new(request(&mut chip, self.dc, LineRequestFlags::OUTPUT, 1, "epd dc")?)?;
+        let busy = CdevPin::new(request(&mut chip, self.busy, LineRequestFlags::INPUT, 0, "epd busy")?)?;
+        let cs = match self.cs {
+            Some(line) => Some(CdevPin::new(request(&mut chip, line, LineRequestFlags::OUTPUT, 1, "epd cs")?)?),
+            None => None,
+        };
+
+        Ok(SpiConnector::new(spi, rst, dc, busy, Delay, chunk_size).with_chip_select(LinuxChipSelect(cs))?)
+    }
+}
+
+impl Default for LinuxConnectorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Largest transfer the spidev driver accepts, set with its `bufsiz`
+/// module parameter.
+pub fn spidev_bufsiz() -> io::Result<usize> {
+    read_bufsiz(Path::new(SPIDEV_BUFSIZ_PATH))
+}
+
+fn read_bufsiz(path: &Path) -> io::Result<usize> {
+    match fs::read_to_string(path)?.trim().parse() {
+        Ok(bufsiz) if bufsiz > 0 => Ok(bufsiz),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected spidev bufsiz")),
+    }
+}
+
+fn request(chip: &mut Chip, line: u32, flags: LineRequestFlags, default: u8, consumer: &str) -> Result<LineHandle, gpio_cdev::Error> {
+    chip.get_line(line)?.request(flags, default, consumer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use crate::linux::{read_bufsiz, LinuxConnectorBuilder, LinuxError};
+
+    #[test]
+    fn test_presets() {
+        let inky = LinuxConnectorBuilder::pimoroni_inky();
+        assert_eq!((inky.rst, inky.dc, inky.busy, inky.cs), (27, 22, 17, None));
+
+        let builder = LinuxConnectorBuilder::new().cs(Some(7)).speed_hz(1_000_000);
+        assert_eq!((builder.rst, builder.dc, builder.busy, builder.cs), (17, 25, 24, Some(7)));
+        assert_eq!(builder.speed_hz, 1_000_000);
+        assert_eq!(builder.chunk_size(Some(256)).effective_chunk_size(), 256);
+    }
+
+    #[test]
+    fn test_read_bufsiz() {
+        let path = env::temp_dir().join(format!("epdriver-bufsiz-{}", process::id()));
+        fs::write(&path, "65536\n").unwrap();
+        assert_eq!(read_bufsiz(&path).unwrap(), 65536);
+        fs::write(&path, "0\n").unwrap();
+        assert!(read_bufsiz(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(read_bufsiz(&path).is_err());
+    }
+
+    #[test]
+    fn test_zero_chunk_size() {
+        let result = LinuxConnectorBuilder::waveshare_hat().chunk_size(Some(0)).build();
+        assert!(matches!(result, Err(LinuxError::Connector(_))));
+    }
+
+    #[test]
+    fn test_missing_spidev() {
+        let result = LinuxConnectorBuilder::waveshare_hat().spidev("/dev/does-not-exist").build();
+        assert!(matches!(result, Err(LinuxError::Spi(_))));
+    }
+}
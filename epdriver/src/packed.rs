@@ -0,0 +1,207 @@
+use crate::controller::display_connector::{Error, Result};
+
+// pixel values are the same as for EPaperDisplay::push_image_with:
+// 0 => black, 1 => accent, anything else => white
+
+/// Bytes per line of a 1bpp plane, lines are padded to a full byte.
+pub fn plane_line_bytes(width: u16) -> usize {
+    (width as usize + 7) / 8
+}
+
+/// Bytes per line of a 4bpp frame, lines are padded to a full byte.
+pub fn nibble_line_bytes(width: u16) -> usize {
+    (width as usize + 1) / 2
+}
+
+// GD7965 in KWR mode with DDX=01: DTM1 bit set => white, DTM2 bit set => accent
+pub(crate) fn bw_bit(val: u8) -> bool {
+    val >= 2
+}
+
+pub(crate) fn accent_bit(val: u8) -> bool {
+    val == 1
+}
+
+// 2bpp grey, 0 black to 3 white: DTM1 takes the high bit and DTM2 the low bit
+pub(crate) fn grey_high_bit(val: u8) -> bool {
+    val.min(3) & 2 != 0
+}
+
+pub(crate) fn grey_low_bit(val: u8) -> bool {
+    val.min(3) & 1 != 0
+}
+
+// packs the 8 pixels starting at x, padding past the end of a line is white
+pub(crate) fn plane_byte<F>(source: &F, x: u32, y: u32, width: u32, bit: fn(u8) -> bool) -> u8 where F: Fn(u32, u32) -> u8 {
+    (0..8).fold(0u8, |byte, i| {
+        let val = if x + i < width { source(x + i, y) } else { 2 };
+        byte | (bit(val) as u8) << (7 - i)
+    })
+}
+
+// IL0371 pixel data, 0x0 black, 0x3 white and 0x4 accent
+pub(crate) fn nibble(val: u8) -> u8 {
+    match val {
+        0 => 0x0,
+        1 => 0x4,
+        _ => 0x3
+    }
+}
+
+/// Black/white and accent 1bpp planes in the layout the GD7965 expects.
+///
+/// The buffers are supplied by the caller and have to be at least
+/// `plane_line_bytes(width) * height` bytes long.
+pub struct PackedPlanes<'a> {
+    width: u16,
+    height: u16,
+    bw: &'a mut [u8],
+    accent: &'a mut [u8],
+}
+
+impl<'a> PackedPlanes<'a> {
+    pub fn new(width: u16, height: u16, bw: &'a mut [u8], accent: &'a mut [u8]) -> Result<PackedPlanes<'a>> {
+        let size = plane_line_bytes(width) * height as usize;
+        if bw.len() < size || accent.len() < size {
+            return Err(Error::BufferSizeError);
+        }
+        Ok(PackedPlanes { width, height, bw: &mut bw[..size], accent: &mut accent[..size] })
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, val: u8) {
+        if x >= self.width as u32 || y >= self.height as u32 {
+            return;
+        }
+        let offset = y as usize * plane_line_bytes(self.width) + x as usize / 8;
+        let mask = 0x80 >> (x % 8);
+        if bw_bit(val) { self.bw[offset] |= mask } else { self.bw[offset] &= !mask }
+        if accent_bit(val) { self.accent[offset] |= mask } else { self.accent[offset] &= !mask }
+    }
+
+    /// Packs a whole frame, `source` is called once per pixel.
+    pub fn fill_with<F>(&mut self, source: F) where F: Fn(u32, u32) -> u8 {
+        let line_bytes = plane_line_bytes(self.width);
+        for y in 0..self.height as u32 {
+            for byte in 0..line_bytes {
+                let mut bw = 0;
+                let mut accent = 0;
+                for bit in 0..8 {
+                    let x = (byte * 8 + bit) as u32;
+                    // padding at the end of a line is white
+                    let val = if x < self.width as u32 { source(x, y) } else { 2 };
+                    bw |= (bw_bit(val) as u8) << (7 - bit);
+                    accent |= (accent_bit(val) as u8) << (7 - bit);
+                }
+                let offset = y as usize * line_bytes + byte;
+                self.bw[offset] = bw;
+                self.accent[offset] = accent;
+            }
+        }
+    }
+
+    pub fn bw(&self) -> &[u8] {
+        self.bw
+    }
+
+    pub fn accent(&self) -> &[u8] {
+        self.accent
+    }
+}
+
+/// 4bpp frame, two pixels per byte, in the layout the IL0371 expects.
+///
+/// The buffer is supplied by the caller and has to be at least
+/// `nibble_line_bytes(width) * height` bytes long.
+pub struct PackedNibbles<'a> {
+    width: u16,
+    height: u16,
+    data: &'a mut [u8],
+}
+
+impl<'a> PackedNibbles<'a> {
+    pub fn new(width: u16, height: u16, data: &'a mut [u8]) -> Result<PackedNibbles<'a>> {
+        let size = nibble_line_bytes(width) * height as usize;
+        if data.len() < size {
+            return Err(Error::BufferSizeError);
+        }
+        Ok(PackedNibbles { width, height, data: &mut data[..size] })
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, val: u8) {
+        if x >= self.width as u32 || y >= self.height as u32 {
+            return;
+        }
+        let offset = y as usize * nibble_line_bytes(self.width) + x as usize / 2;
+        // first pixel goes into the high nibble
+        let shift = (1 - x % 2) * 4;
+        self.data[offset] = (self.data[offset] & !(0xf << shift)) | nibble(val) << shift;
+    }
+
+    /// Packs a whole frame, `source` is called once per pixel.
+    pub fn fill_with<F>(&mut self, source: F) where F: Fn(u32, u32) -> u8 {
+        let line_bytes = nibble_line_bytes(self.width);
+        for y in 0..self.height as u32 {
+            for byte in 0..line_bytes {
+                let x = byte as u32 * 2;
+                let p1 = nibble(source(x, y)) << 4;
+                // padding at the end of a line is white
+                let p2 = nibble(if x + 1 < self.width as u32 { source(x + 1, y) } else { 2 });
+                self.data[y as usize * line_bytes + byte] = p1 | p2;
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packed::{PackedNibbles, PackedPlanes};
+
+    #[test]
+    fn test_planes_fill_with() {
+        let mut bw = [0u8; 4];
+        let mut accent = [0u8; 4];
+        let mut planes = PackedPlanes::new(10, 2, &mut bw, &mut accent).unwrap();
+
+        planes.fill_with(|x, y| if y == 0 { (x % 3) as u8 } else { 2 });
+
+        // 0,1,2,0,1,2,0,1 | 2,0 + white padding
+        assert_eq!(planes.bw(), &[0b0010_0100, 0b1011_1111, 0xff, 0xff]);
+        assert_eq!(planes.accent(), &[0b0100_1001, 0b0000_0000, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_planes_set_pixel() {
+        let mut bw = [0xffu8; 2];
+        let mut accent = [0u8; 2];
+        let mut planes = PackedPlanes::new(16, 1, &mut bw, &mut accent).unwrap();
+
+        planes.set_pixel(0, 0, 0);
+        planes.set_pixel(9, 0, 1);
+        planes.set_pixel(16, 0, 0);
+
+        assert_eq!(planes.bw(), &[0x7f, 0xbf]);
+        assert_eq!(planes.accent(), &[0x00, 0x40]);
+    }
+
+    #[test]
+    fn test_planes_buffer_too_small() {
+        let mut bw = [0u8; 99];
+        let mut accent = [0u8; 100];
+        assert!(PackedPlanes::new(800, 1, &mut bw, &mut accent).is_err());
+    }
+
+    #[test]
+    fn test_nibbles_fill_with() {
+        let mut data = [0u8; 4];
+        let mut nibbles = PackedNibbles::new(3, 2, &mut data).unwrap();
+
+        nibbles.fill_with(|x, y| if y == 0 { x as u8 } else { 1 });
+        nibbles.set_pixel(2, 1, 0);
+
+        assert_eq!(nibbles.data(), &[0x04, 0x33, 0x44, 0x03]);
+    }
+}
@@ -0,0 +1,271 @@
+use embedded_hal::blocking::delay::DelayMs;
+
+use crate::controller::display_connector::{Error, Result};
+use crate::display::{Border, EPaperDisplay, Window};
+
+/// How often and how patiently `Recovering` retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// recoveries before the error is returned, 0 never retries
+    pub max_attempts: u8,
+    /// delay before the first recovery, doubled for every further one
+    pub initial_backoff_ms: u16,
+    pub max_backoff_ms: u16,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        RecoveryPolicy { max_attempts: 3, initial_backoff_ms: 500, max_backoff_ms: 8000 }
+    }
+}
+
+impl RecoveryPolicy {
+    /// Delay before recovery `attempt`, counting from 1.
+    pub fn backoff_ms(&self, attempt: u8) -> u16 {
+        let shift = attempt.saturating_sub(1).min(15) as u32;
+        (self.initial_backoff_ms as u32).checked_shl(shift).unwrap_or(u32::MAX).min(self.max_backoff_ms as u32) as u16
+    }
+}
+
+// errors that say something about the wiring or the controller state,
+// settings and buffer sizes won't get better by resetting
+fn is_recoverable(error: &Error) -> bool {
+    match error {
+        Error::SpiWriteError | Error::SpiReadError | Error::DelayError | Error::BusyPinReadError
+        | Error::ResetPinWriteError | Error::DcPinWriteError | Error::CsPinWriteError | Error::BusyTimeout => true,
+        Error::ReadUnsupported | Error::BufferSizeError | Error::ConfigError(_)
+        | Error::RecordWriteError | Error::RecordFormatError | Error::RemoteError => false,
+    }
+}
+
+/// Wraps a display and recovers from connector errors and busy timeouts
+/// by resetting and initialising the panel again, `init` resets the
+/// controller first on every panel.
+///
+/// The failed operation is retried after every recovery. A refresh that
+/// only fails in `wait` can't be repeated, the frame is lost then, so
+/// prefer `push_image_with` over `start_image_with` and `wait`.
+pub struct Recovering<D, DELAY, F = fn(u8, &Error)> where D: EPaperDisplay, DELAY: DelayMs<u16>, F: FnMut(u8, &Error) {
+    display: D,
+    policy: RecoveryPolicy,
+    delay: DELAY,
+    on_attempt: F,
+}
+
+fn ignore_attempt(_attempt: u8, _error: &Error) {}
+
+impl<D, DELAY> Recovering<D, DELAY> where D: EPaperDisplay, DELAY: DelayMs<u16> {
+    pub fn new(display: D, policy: RecoveryPolicy, delay: DELAY) -> Recovering<D, DELAY> {
+        Recovering { display, policy, delay, on_attempt: ignore_attempt }
+    }
+}
+
+impl<D, DELAY, F> Recovering<D, DELAY, F> where D: EPaperDisplay, DELAY: DelayMs<u16>, F: FnMut(u8, &Error) {
+    /// Like `new`, `on_attempt` is called with the attempt number and the
+    /// error that caused it before every recovery.
+    pub fn with_callback(display: D, policy: RecoveryPolicy, delay: DELAY, on_attempt: F) -> Recovering<D, DELAY, F> {
+        Recovering { display, policy, delay, on_attempt }
+    }
+
+    pub fn policy(&self) -> &RecoveryPolicy {
+        &self.policy
+    }
+
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    pub fn release(self) -> D {
+        self.display
+    }
+
+    fn retry<R, OP>(&mut self, mut op: OP) -> Result<R> where OP: FnMut(&mut D) -> Result<R> {
+        let mut attempts = 0;
+        loop {
+            let mut error = match op(&mut self.display) {
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+            // a failing init counts as another failed attempt
+            loop {
+                if !is_recoverable(&error) || attempts >= self.policy.max_attempts {
+                    return Err(error);
+                }
+                attempts += 1;
+                (self.on_attempt)(attempts, &error);
+                self.delay.delay_ms(self.policy.backoff_ms(attempts));
+                match self.display.init() {
+                    Ok(()) => break,
+                    Err(e) => error = e,
+                }
+            }
+        }
+    }
+}
+
+impl<D, DELAY, F> EPaperDisplay for Recovering<D, DELAY, F> where D: EPaperDisplay, DELAY: DelayMs<u16>, F: FnMut(u8, &Error) {
+    fn init(&mut self) -> Result<()> {
+        self.retry(|display| display.init())
+    }
+
+    fn set_border(&mut self, border: Border) {
+        self.display.set_border(border)
+    }
+
+    fn push_image_with<S>(&mut self, source: S) -> Result<()> where S: Fn(u32, u32) -> u8 {
+        self.retry(|display| display.push_image_with(&source))
+    }
+
+    fn start_image_with<S>(&mut self, source: S) -> Result<()> where S: Fn(u32, u32) -> u8 {
+        self.retry(|display| display.start_image_with(&source))
+    }
+
+    fn is_refreshing(&mut self) -> Result<bool> {
+        self.retry(|display| display.is_refreshing())
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        self.retry(|display| display.wait())
+    }
+
+    fn push_window_with<S>(&mut self, window: Window, source: S) -> Result<()> where S: Fn(u32, u32) -> u8 {
+        self.retry(|display| display.push_window_with(window, &source))
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        self.display.supports_partial_refresh()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.retry(|display| display.clear())
+    }
+
+    fn width(&self) -> u16 {
+        self.display.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.display.height()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use embedded_hal::blocking::delay::DelayMs;
+    use crate::controller::display_connector::{Error, Result};
+    use crate::display::{Border, EPaperDisplay};
+    use crate::recovery::{Recovering, RecoveryPolicy};
+
+    // fails the next `failures` operations, init included
+    struct FlakyDisplay {
+        failures: u32,
+        error: fn() -> Error,
+        inits: u32,
+        pushes: u32,
+    }
+
+    impl FlakyDisplay {
+        fn new(failures: u32, error: fn() -> Error) -> FlakyDisplay {
+            FlakyDisplay { failures, error, inits: 0, pushes: 0 }
+        }
+
+        fn fail(&mut self) -> Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err((self.error)());
+            }
+            Ok(())
+        }
+    }
+
+    impl EPaperDisplay for FlakyDisplay {
+        fn init(&mut self) -> Result<()> {
+            self.inits += 1;
+            self.fail()
+        }
+
+        fn set_border(&mut self, _border: Border) {}
+
+        fn start_image_with<F>(&mut self, source: F) -> Result<()> where F: Fn(u32, u32) -> u8 {
+            self.fail()?;
+            assert_eq!(source(0, 0), 1);
+            self.pushes += 1;
+            Ok(())
+        }
+
+        fn is_refreshing(&mut self) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn wait(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn width(&self) -> u16 {
+            8
+        }
+
+        fn height(&self) -> u16 {
+            1
+        }
+    }
+
+    #[derive(Default)]
+    struct DelayRecorder {
+        delays: Vec<u16>,
+    }
+
+    impl DelayMs<u16> for DelayRecorder {
+        fn delay_ms(&mut self, ms: u16) {
+            self.delays.push(ms);
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RecoveryPolicy { max_attempts: 20, initial_backoff_ms: 300, max_backoff_ms: 2000 };
+        assert_eq!(policy.backoff_ms(1), 300);
+        assert_eq!(policy.backoff_ms(3), 1200);
+        assert_eq!(policy.backoff_ms(4), 2000);
+        assert_eq!(policy.backoff_ms(20), 2000);
+    }
+
+    #[test]
+    fn test_recovers_after_failures() {
+        let mut attempts = Vec::new();
+        let display = FlakyDisplay::new(3, || Error::BusyTimeout);
+        let mut recovering = Recovering::with_callback(display, RecoveryPolicy::default(), DelayRecorder::default(),
+            |attempt, error| attempts.push((attempt, matches!(error, Error::BusyTimeout))));
+
+        // the push and the first two re-inits fail, the third re-init works
+        recovering.push_image_with(|_, _| 1).unwrap();
+        assert_eq!(recovering.delay.delays, [500, 1000, 2000]);
+        let display = recovering.release();
+        assert_eq!((display.inits, display.pushes), (3, 1));
+        assert_eq!(attempts, [(1, true), (2, true), (3, true)]);
+    }
+
+    #[test]
+    fn test_gives_up() {
+        let display = FlakyDisplay::new(10, || Error::SpiWriteError);
+        let policy = RecoveryPolicy { max_attempts: 2, ..RecoveryPolicy::default() };
+        let mut recovering = Recovering::new(display, policy, DelayRecorder::default());
+        assert!(matches!(recovering.push_image_with(|_, _| 1), Err(Error::SpiWriteError)));
+        assert_eq!(recovering.display().inits, 2);
+
+        // configuration errors are returned straight away
+        let display = FlakyDisplay::new(1, || Error::BufferSizeError);
+        let mut recovering = Recovering::new(display, policy, DelayRecorder::default());
+        assert!(matches!(recovering.push_image_with(|_, _| 1), Err(Error::BufferSizeError)));
+        assert_eq!(recovering.display().inits, 0);
+    }
+}
@@ -0,0 +1,344 @@
+//! Mocks and checks shared by the tests of the controllers and panels.
+
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::result;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::controller::display_connector::{DisplayConnector, Error, Result};
+use crate::controller::gd7965;
+use crate::controller::uc_family::opcode;
+
+// data longer than this is summarised in golden sequences
+const GOLDEN_INLINE_BYTES: usize = 16;
+
+pub(crate) struct MockPin {
+    #[allow(dead_code)]
+    pub(crate) name: &'static str,
+    pub(crate) state: bool,
+}
+
+impl OutputPin for MockPin {
+    type Error = ();
+
+    fn set_low(&mut self) -> result::Result<(), Self::Error> {
+        self.state = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> result::Result<(), Self::Error> {
+        self.state = true;
+        Ok(())
+    }
+}
+
+impl InputPin for MockPin {
+    type Error = ();
+
+    fn is_high(&self) -> result::Result<bool, Self::Error> {
+        Ok(self.state)
+    }
+
+    fn is_low(&self) -> result::Result<bool, Self::Error> {
+        Ok(!self.state)
+    }
+}
+
+pub(crate) struct MockDelay {}
+
+impl DelayMs<u16> for MockDelay {
+    fn delay_ms(&mut self, _ms: u16) {}
+}
+
+impl DelayUs<u16> for MockDelay {
+    fn delay_us(&mut self, _us: u16) {}
+}
+
+// output pin that records every level it is set to in a log shared
+// with other pins, so the order across pins can be checked
+pub(crate) struct LoggingPin<'a> {
+    pub(crate) name: &'static str,
+    pub(crate) log: &'a RefCell<Vec<(&'static str, bool)>>,
+}
+
+impl OutputPin for LoggingPin<'_> {
+    type Error = ();
+
+    fn set_low(&mut self) -> result::Result<(), Self::Error> {
+        self.log.borrow_mut().push((self.name, false));
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> result::Result<(), Self::Error> {
+        self.log.borrow_mut().push((self.name, true));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Reset(u16),
+    Command(u8),
+    /// consecutive data is one transfer as far as the controller is concerned
+    Data(Vec<u8>),
+    Delay(u16),
+    Busy(bool),
+    Read(usize),
+}
+
+/// Connector that records everything a display does with it. BUSY is
+/// asserted for `busy_reads` reads after power on, power off and refresh,
+/// reads return 0xa0, 0xa1, ...
+#[derive(Default)]
+pub(crate) struct MockPanel {
+    // is_busy only gets &self
+    ops: RefCell<Vec<Op>>,
+    busy_reads: u32,
+    busy_left: Cell<u32>,
+    fail_command: Option<u8>,
+    fail_data: bool,
+    // Error isn't Clone, SpiWriteError if not set
+    failure: Option<fn() -> Error>,
+}
+
+impl MockPanel {
+    pub(crate) fn with_busy_reads(busy_reads: u32) -> MockPanel {
+        MockPanel { busy_reads, ..MockPanel::default() }
+    }
+
+    /// Makes sending `command` fail, `None` lets every command through again.
+    pub(crate) fn fail_on(&mut self, command: Option<u8>) {
+        self.fail_command = command;
+    }
+
+    /// Makes sending any data fail.
+    pub(crate) fn fail_data(&mut self, fail: bool) {
+        self.fail_data = fail;
+    }
+
+    /// The error refused commands and data fail with, instead of
+    /// `Error::SpiWriteError`.
+    pub(crate) fn fail_with(&mut self, failure: fn() -> Error) {
+        self.failure = Some(failure);
+    }
+
+    fn failure(&self) -> Error {
+        self.failure.map_or(Error::SpiWriteError, |failure| failure())
+    }
+
+    /// How often BUSY was read as asserted.
+    pub(crate) fn busy_polls(&self) -> usize {
+        self.ops.borrow().iter().filter(|op| **op == Op::Busy(true)).count()
+    }
+
+    /// Forgets what was recorded so far, to look at one operation at a time.
+    pub(crate) fn clear(&mut self) {
+        self.ops.get_mut().clear();
+    }
+
+    /// The data sent after every `command`, joined together.
+    pub(crate) fn sent(&self, command: u8) -> Vec<u8> {
+        let ops = self.ops.borrow();
+        let mut data = Vec::new();
+        for pair in ops.windows(2) {
+            if let [Op::Command(c), Op::Data(d)] = pair {
+                if *c == command {
+                    data.extend_from_slice(d);
+                }
+            }
+        }
+        data
+    }
+
+    pub(crate) fn golden(&self) -> Vec<String> {
+        golden(&self.ops.borrow())
+    }
+}
+
+impl DisplayConnector for MockPanel {
+    fn reset(&mut self, low_ms: u16) -> Result<()> {
+        self.ops.get_mut().push(Op::Reset(low_ms));
+        Ok(())
+    }
+
+    fn is_busy(&self) -> Result<bool> {
+        let left = self.busy_left.get();
+        self.busy_left.set(left.saturating_sub(1));
+        self.ops.borrow_mut().push(Op::Busy(left > 0));
+        Ok(left > 0)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<()> {
+        if self.fail_command == Some(command) {
+            return Err(self.failure());
+        }
+        self.ops.get_mut().push(Op::Command(command));
+        if let opcode::PON | opcode::POF | opcode::DRF = command {
+            self.busy_left.set(self.busy_reads);
+        }
+        Ok(())
+    }
+
+    fn send_data_with<F>(&mut self, repeats: u32, source: F) -> Result<()> where F: Fn(u32) -> u8 {
+        self.send_data(&(0..repeats).map(source).collect::<Vec<u8>>())
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        if self.fail_data {
+            return Err(self.failure());
+        }
+        let ops = self.ops.get_mut();
+        match ops.last_mut() {
+            Some(Op::Data(previous)) => previous.extend_from_slice(data),
+            _ => ops.push(Op::Data(data.to_vec())),
+        }
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, ms: u16) -> Result<()> {
+        self.ops.get_mut().push(Op::Delay(ms));
+        Ok(())
+    }
+
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.ops.get_mut().push(Op::Read(buffer.len()));
+        buffer.iter_mut().enumerate().for_each(|(i, b)| *b = 0xa0 + i as u8);
+        Ok(())
+    }
+}
+
+/// Renders recorded operations one per line, so a sequence can be compared
+/// against a snapshot that reads like a datasheet:
+///
+/// * `reset 2` and `delay 10`
+/// * `61 03 20 01 e0`, a command and its data in hex
+/// * `10 [48000 x ff]` for long uniform data, `[N bytes, fnv 0x...]` for
+///   other long data
+/// * `wait` where BUSY was polled until it cleared, however long that took,
+///   `busy` where it was read and found asserted
+/// * `read 3` for the number of bytes read
+fn golden(ops: &[Op]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        if let Some((len, mut busy)) = poll(&ops[i..]) {
+            i += len;
+            // the polls of one wait, with the delays in between
+            while busy {
+                match (ops.get(i), ops.get(i + 1..).and_then(poll)) {
+                    (Some(Op::Delay(_)), Some((len, next))) => {
+                        i += 1 + len;
+                        busy = next;
+                    }
+                    _ => break,
+                }
+            }
+            lines.push(String::from(if busy { "busy" } else { "wait" }));
+            continue;
+        }
+        match &ops[i] {
+            Op::Reset(ms) => lines.push(format!("reset {}", ms)),
+            Op::Delay(ms) => lines.push(format!("delay {}", ms)),
+            Op::Read(len) => lines.push(format!("read {}", len)),
+            Op::Command(command) => match ops.get(i + 1) {
+                Some(Op::Data(data)) => {
+                    lines.push(format!("{:02x} {}", command, hex(data)));
+                    i += 1;
+                }
+                _ => lines.push(format!("{:02x}", command)),
+            },
+            Op::Data(data) => lines.push(format!("data {}", hex(data))),
+            Op::Busy(_) => unreachable!("taken as a poll"),
+        }
+        i += 1;
+    }
+    lines
+}
+
+// a BUSY read and its length, the GD7965 asks for its status first
+fn poll(ops: &[Op]) -> Option<(usize, bool)> {
+    match ops {
+        [Op::Command(gd7965::opcode::FLG), Op::Busy(busy), ..] => Some((2, *busy)),
+        [Op::Busy(busy), ..] => Some((1, *busy)),
+        _ => None,
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    if data.len() <= GOLDEN_INLINE_BYTES {
+        let bytes: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+        return bytes.join(" ");
+    }
+    if data.iter().all(|&b| b == data[0]) {
+        return format!("[{} x {:02x}]", data.len(), data[0]);
+    }
+    let fnv = data.iter().fold(0x811c_9dc5u32, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193));
+    format!("[{} bytes, fnv 0x{:08x}]", data.len(), fnv)
+}
+
+/// Pixel values a source is expected to show, white normalised to 2.
+pub(crate) fn expected_pixels<F>(width: u32, height: u32, source: F) -> Vec<u8> where F: Fn(u32, u32) -> u8 {
+    (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| source(x, y).min(2)).collect()
+}
+
+/// Pixel values of GD7965 black/white and accent planes, 0xff where the
+/// bits don't make sense. Panics unless the padding at the end of every
+/// line is white.
+pub(crate) fn unpack_planes(bw: &[u8], accent: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let line_bytes = (width as usize + 7) / 8;
+    assert_eq!((bw.len(), accent.len()), (line_bytes * height as usize, line_bytes * height as usize), "plane sizes");
+    let mut pixels = Vec::new();
+    for y in 0..height as usize {
+        for x in 0..line_bytes * 8 {
+            let offset = y * line_bytes + x / 8;
+            let mask = 0x80 >> (x % 8);
+            let val = match (bw[offset] & mask != 0, accent[offset] & mask != 0) {
+                (false, false) => 0,
+                (false, true) => 1,
+                (true, false) => 2,
+                (true, true) => 0xff,
+            };
+            if x < width as usize {
+                pixels.push(val);
+            } else {
+                assert_eq!(val, 2, "padding at x {} of line {}", x, y);
+            }
+        }
+    }
+    pixels
+}
+
+/// Pixel values of an IL0371 4bpp frame, 0xff for anything but black,
+/// accent and white. Panics unless the padding at the end of every line is
+/// white.
+pub(crate) fn unpack_nibbles(data: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let line_bytes = (width as usize + 1) / 2;
+    assert_eq!(data.len(), line_bytes * height as usize, "frame size");
+    let mut pixels = Vec::new();
+    for y in 0..height as usize {
+        for x in 0..line_bytes * 2 {
+            let byte = data[y * line_bytes + x / 2];
+            let val = match if x % 2 == 0 { byte >> 4 } else { byte & 0x0f } {
+                0x0 => 0,
+                0x4 => 1,
+                0x3 => 2,
+                _ => 0xff,
+            };
+            if x < width as usize {
+                pixels.push(val);
+            } else {
+                assert_eq!(val, 2, "padding at x {} of line {}", x, y);
+            }
+        }
+    }
+    pixels
+}
+
+// black, accent and white in every position of a byte and across line
+// ends, lines start with a white above 2
+pub(crate) fn line_end_pattern(x: u32, y: u32) -> u8 {
+    ((x + 2 * y) % 3) as u8 + if x == 0 { 3 } else { 0 }
+}